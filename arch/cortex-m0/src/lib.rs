@@ -19,6 +19,8 @@ pub unsafe extern "C" fn generic_isr() {}
 pub unsafe extern "C" fn generic_isr() {
     asm!(
         "
+    /* SYSCALL_FIRED is not set here, so the kernel will see that the */
+    /* process was interrupted rather than having made a syscall. */
     /* Skip saving process state if not coming from user-space */
     ldr r0, MEXC_RETURN_PSP
     cmp lr, r0
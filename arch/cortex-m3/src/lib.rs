@@ -19,6 +19,8 @@ pub use cortexm::systick;
 pub unsafe extern "C" fn systick_handler() {
     asm!(
         "
+        /* SYSCALL_FIRED is not set here, so the kernel will see that the */
+        /* process was interrupted rather than having made a syscall. */
         /* Skip saving process state if not coming from user-space */
         cmp lr, #0xfffffffd
         bne _systick_handler_no_stacking
@@ -47,6 +49,8 @@ pub unsafe extern "C" fn systick_handler() {
 pub unsafe extern "C" fn generic_isr() {
     asm!(
         "
+    /* SYSCALL_FIRED is not set here, so the kernel will see that the */
+    /* process was interrupted rather than having made a syscall. */
     /* Skip saving process state if not coming from user-space */
     cmp lr, #0xfffffffd
     bne _ggeneric_isr_no_stacking
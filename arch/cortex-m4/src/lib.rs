@@ -32,6 +32,14 @@ extern "C" {
     static mut _erelocate: u32;
 }
 
+/// The EXC_RETURN value of the SVC the kernel switches to a process with.
+/// Exceptions taken from the process return to the kernel with it, so that
+/// the kernel's frame is unstacked the way it was stacked, which is as an
+/// extended frame if the kernel had an active floating point context.
+#[no_mangle]
+#[used]
+pub static mut KERNEL_EXC_RETURN: u32 = 0xFFFFFFF9;
+
 #[cfg(not(target_os = "none"))]
 pub unsafe extern "C" fn systick_handler() {}
 
@@ -40,23 +48,39 @@ pub unsafe extern "C" fn systick_handler() {}
 pub unsafe extern "C" fn systick_handler() {
     asm!(
         "
+    /* SYSCALL_FIRED is not set here, so the kernel will see that the */
+    /* process was interrupted rather than having made a syscall. */
     /* Skip saving process state if not coming from user-space */
-    cmp lr, #0xfffffffd
-    bne _systick_handler_no_stacking
+    tst lr, #4
+    beq _systick_handler_no_stacking
 
     /* We need the most recent kernel's version of r1, which points */
     /* to the Process struct's stored registers field. The kernel's r1 */
     /* lives in the second word of the hardware stacked registers on MSP */
     mov r1, sp
     ldr r1, [r1, #4]
-    stmia r1, {r4-r11}
-  _systick_handler_no_stacking:
+    stmia r1, {r4-r11}"
+    );
+    #[cfg(feature = "fpu")]
+    asm!(
+        "
+    mov r1, sp
+    ldr r1, [r1, #4]
+    /* Record whether the hardware stacked an extended (floating point) */
+    /* frame, which is the case if bit 4 of EXC_RETURN is clear. */
+    and r0, lr, #0x10
+    eor r0, r0, #0x10
+    str r0, [r1, #96]"
+    );
+    asm!(
+        "
     /* Set thread mode to privileged */
     mov r0, #0
     msr CONTROL, r0
 
-    movw LR, #0xFFF9
-    movt LR, #0xFFFF"
+    ldr r0, =KERNEL_EXC_RETURN
+    ldr lr, [r0, #0]
+  _systick_handler_no_stacking:"
     );
 }
 
@@ -69,23 +93,38 @@ pub unsafe extern "C" fn generic_isr() {}
 pub unsafe extern "C" fn generic_isr() {
     asm!(
         "
+    /* SYSCALL_FIRED is not set here, so the kernel will see that the */
+    /* process was interrupted rather than having made a syscall. */
     /* Skip saving process state if not coming from user-space */
-    cmp lr, #0xfffffffd
-    bne _ggeneric_isr_no_stacking
+    tst lr, #4
+    beq _ggeneric_isr_no_stacking
 
     /* We need the most recent kernel's version of r1, which points */
     /* to the Process struct's stored registers field. The kernel's r1 */
     /* lives in the second word of the hardware stacked registers on MSP */
     mov r1, sp
     ldr r1, [r1, #4]
-    stmia r1, {r4-r11}
-
+    stmia r1, {r4-r11}"
+    );
+    #[cfg(feature = "fpu")]
+    asm!(
+        "
+    mov r1, sp
+    ldr r1, [r1, #4]
+    /* Record whether the hardware stacked an extended (floating point) */
+    /* frame, which is the case if bit 4 of EXC_RETURN is clear. */
+    and r0, lr, #0x10
+    eor r0, r0, #0x10
+    str r0, [r1, #96]"
+    );
+    asm!(
+        "
     /* Set thread mode to privileged */
     mov r0, #0
     msr CONTROL, r0
 
-    movw LR, #0xFFF9
-    movt LR, #0xFFFF
+    ldr r0, =KERNEL_EXC_RETURN
+    ldr lr, [r0, #0]
   _ggeneric_isr_no_stacking:
     /* Find the ISR number by looking at the low byte of the IPSR registers */
     mrs r0, IPSR
@@ -126,14 +165,20 @@ pub unsafe extern "C" fn generic_isr() {
 #[cfg(not(target_os = "none"))]
 pub unsafe extern "C" fn svc_handler() {}
 
-#[cfg(target_os = "none")]
+#[cfg(all(target_os = "none", not(feature = "fpu")))]
 #[naked]
 pub unsafe extern "C" fn svc_handler() {
     asm!(
         "
-    cmp lr, #0xfffffff9
+    /* Bit 2 of EXC_RETURN is clear if the SVC came from the kernel, on */
+    /* the MSP, rather than from a process */
+    tst lr, #4
     bne to_kernel
 
+    /* Save the kernel's EXC_RETURN to return to it with later */
+    ldr r0, =KERNEL_EXC_RETURN
+    str lr, [r0, #0]
+
     /* Set thread mode to unprivileged */
     mov r0, #1
     msr CONTROL, r0
@@ -150,8 +195,59 @@ pub unsafe extern "C" fn svc_handler() {
     mov r0, #0
     msr CONTROL, r0
 
-    movw LR, #0xFFF9
-    movt LR, #0xFFFF"
+    ldr r0, =KERNEL_EXC_RETURN
+    ldr lr, [r0, #0]"
+    );
+}
+
+#[cfg(all(target_os = "none", feature = "fpu"))]
+#[naked]
+pub unsafe extern "C" fn svc_handler() {
+    asm!(
+        "
+    /* The kernel's r1 points to the Process struct's stored registers */
+    /* field, see `systick_handler` */
+    mov r1, sp
+    ldr r1, [r1, #4]
+
+    /* Bit 2 of EXC_RETURN is clear if the SVC came from the kernel, on */
+    /* the MSP, rather than from a process. The kernel's own EXC_RETURN */
+    /* may have bit 4 clear too, as switch_to_user uses the FPU. */
+    tst lr, #4
+    bne to_kernel
+
+    /* Save the kernel's EXC_RETURN to return to it with later */
+    ldr r0, =KERNEL_EXC_RETURN
+    str lr, [r0, #0]
+
+    /* Set thread mode to unprivileged */
+    mov r0, #1
+    msr CONTROL, r0
+
+    /* Return with an extended frame if that is what the process stacked */
+    /* when it last entered the kernel, by clearing bit 4 of EXC_RETURN */
+    ldr r0, [r1, #96]
+    movw lr, #0xfffd
+    movt lr, #0xffff
+    eor lr, lr, r0
+    bx lr
+  to_kernel:
+    /* Record whether the hardware stacked an extended (floating point) */
+    /* frame, which is the case if bit 4 of EXC_RETURN is clear. */
+    and r0, lr, #0x10
+    eor r0, r0, #0x10
+    str r0, [r1, #96]
+
+    ldr r0, =SYSCALL_FIRED
+    mov r1, #1
+    str r1, [r0, #0]
+
+    /* Set thread mode to privileged */
+    mov r0, #0
+    msr CONTROL, r0
+
+    ldr r0, =KERNEL_EXC_RETURN
+    ldr lr, [r0, #0]"
     );
 }
 
@@ -160,7 +256,7 @@ pub unsafe extern "C" fn switch_to_user(user_stack: *const u8, process_got: *con
     user_stack as *mut u8
 }
 
-#[cfg(target_os = "none")]
+#[cfg(all(target_os = "none", not(feature = "fpu")))]
 #[no_mangle]
 /// r0 is top of user stack, r1 Process GOT
 pub unsafe extern "C" fn switch_to_user(
@@ -189,6 +285,74 @@ pub unsafe extern "C" fn switch_to_user(
     user_stack as *mut u8
 }
 
+#[cfg(all(target_os = "none", feature = "fpu"))]
+#[no_mangle]
+/// r0 is top of user stack, r1 Process GOT
+///
+/// The process's s16-s31 are callee-saved and not part of the exception
+/// frame, so they are loaded from and stored back to the words following
+/// r4-r11 in the Process struct's regs field.
+pub unsafe extern "C" fn switch_to_user(
+    mut user_stack: *const u8,
+    process_regs: &mut [usize; 25],
+) -> *mut u8 {
+    asm!("
+    /* Load bottom of stack into Process Stack Pointer */
+    msr psp, $0
+
+    /* Load non-hardware-stacked registers from Process stack */
+    /* Ensure that $2 is stored in a callee saved register */
+    ldmia $2, {r4-r11}
+    add r0, $2, #32
+    vldmia r0, {s16-s31}
+
+    /* SWITCH */
+    svc 0xff /* It doesn't matter which SVC number we use here */
+
+    /* Push non-hardware-stacked registers into Process struct's */
+    /* regs field */
+    stmia $2, {r4-r11}
+    add r0, $2, #32
+    vstmia r0, {s16-s31}
+
+    mrs $0, PSP /* PSP into r0 */"
+    : "={r0}"(user_stack)
+    : "{r0}"(user_stack), "{r1}"(process_regs)
+    : "r4","r5","r6","r7","r8","r9","r10","r11",
+      "s16","s17","s18","s19","s20","s21","s22","s23",
+      "s24","s25","s26","s27","s28","s29","s30","s31");
+    user_stack as *mut u8
+}
+
+#[cfg(all(not(target_os = "none"), feature = "armv8m"))]
+pub unsafe extern "C" fn set_process_stack_limit(_limit: *const u8) {}
+
+#[cfg(all(target_os = "none", feature = "armv8m"))]
+#[no_mangle]
+/// Set the Process Stack Pointer Limit register. A process whose stack
+/// pointer goes below `limit` takes a stack overflow UsageFault.
+pub unsafe extern "C" fn set_process_stack_limit(limit: *const u8) {
+    asm!("msr psplim, $0" : : "r"(limit) : : "volatile");
+}
+
+/// Give unprivileged code access to the FPU, and have the hardware stack the
+/// floating point context eagerly on exception entry. Lazy stacking would
+/// leave s0-s15 of an interrupted process in the FPU, where the next process
+/// to run would overwrite them. Boards with the `fpu` feature must call this
+/// before starting any processes.
+#[cfg(feature = "fpu")]
+pub unsafe fn enable_fpu() {
+    // CPACR: full access to coprocessors 10 and 11.
+    let cpacr = 0xE000ED88 as *mut u32;
+    core::ptr::write_volatile(cpacr, core::ptr::read_volatile(cpacr) | 0xf << 20);
+    // FPCCR: set ASPEN (bit 31), clear LSPEN (bit 30).
+    let fpccr = 0xE000EF34 as *mut u32;
+    core::ptr::write_volatile(
+        fpccr,
+        (core::ptr::read_volatile(fpccr) | 1 << 31) & !(1 << 30),
+    );
+}
+
 pub unsafe extern "C" fn hard_fault_handler() {
     use core::intrinsics::offset;
 
@@ -221,35 +385,12 @@ pub unsafe extern "C" fn hard_fault_handler() {
         let mode_str = "Kernel";
 
         let shcsr: u32 = core::ptr::read_volatile(0xE000ED24 as *const u32);
-        let cfsr: u32 = core::ptr::read_volatile(0xE000ED28 as *const u32);
-        let hfsr: u32 = core::ptr::read_volatile(0xE000ED2C as *const u32);
-        let mmfar: u32 = core::ptr::read_volatile(0xE000ED34 as *const u32);
-        let bfar: u32 = core::ptr::read_volatile(0xE000ED38 as *const u32);
-
-        let iaccviol = (cfsr & 0x01) == 0x01;
-        let daccviol = (cfsr & 0x02) == 0x02;
-        let munstkerr = (cfsr & 0x08) == 0x08;
-        let mstkerr = (cfsr & 0x10) == 0x10;
-        let mlsperr = (cfsr & 0x20) == 0x20;
-        let mmfarvalid = (cfsr & 0x80) == 0x80;
-
-        let ibuserr = ((cfsr >> 8) & 0x01) == 0x01;
-        let preciserr = ((cfsr >> 8) & 0x02) == 0x02;
-        let impreciserr = ((cfsr >> 8) & 0x04) == 0x04;
-        let unstkerr = ((cfsr >> 8) & 0x08) == 0x08;
-        let stkerr = ((cfsr >> 8) & 0x10) == 0x10;
-        let lsperr = ((cfsr >> 8) & 0x20) == 0x20;
-        let bfarvalid = ((cfsr >> 8) & 0x80) == 0x80;
-
-        let undefinstr = ((cfsr >> 16) & 0x01) == 0x01;
-        let invstate = ((cfsr >> 16) & 0x02) == 0x02;
-        let invpc = ((cfsr >> 16) & 0x04) == 0x04;
-        let nocp = ((cfsr >> 16) & 0x08) == 0x08;
-        let unaligned = ((cfsr >> 16) & 0x100) == 0x100;
-        let divbysero = ((cfsr >> 16) & 0x200) == 0x200;
-
-        let vecttbl = (hfsr & 0x02) == 0x02;
-        let forced = (hfsr & 0x40000000) == 0x40000000;
+        let fault_status = kernel::procs::FaultStatus {
+            cfsr: core::ptr::read_volatile(0xE000ED28 as *const u32),
+            hfsr: core::ptr::read_volatile(0xE000ED2C as *const u32),
+            mmfar: core::ptr::read_volatile(0xE000ED34 as *const u32),
+            bfar: core::ptr::read_volatile(0xE000ED38 as *const u32),
+        };
 
         let ici_it = (((stacked_xpsr >> 25) & 0x3) << 6) | ((stacked_xpsr >> 10) & 0x3f);
         let thumb_bit = ((stacked_xpsr >> 24) & 0x1) == 1;
@@ -270,30 +411,7 @@ pub unsafe extern "C" fn hard_fault_handler() {
              \ttop of stack     0x{:x}\n\
              \tbottom of stack  0x{:x}\n\
              \tSHCSR 0x{:x}\n\
-             \tCFSR  0x{:x}\n\
-             \tHSFR  0x{:x}\n\
-             \tInstruction Access Violation:       {}\n\
-             \tData Access Violation:              {}\n\
-             \tMemory Management Unstacking Fault: {}\n\
-             \tMemory Management Stacking Fault:   {}\n\
-             \tMemory Management Lazy FP Fault:    {}\n\
-             \tInstruction Bus Error:              {}\n\
-             \tPrecise Data Bus Error:             {}\n\
-             \tImprecise Data Bus Error:           {}\n\
-             \tBus Unstacking Fault:               {}\n\
-             \tBus Stacking Fault:                 {}\n\
-             \tBus Lazy FP Fault:                  {}\n\
-             \tUndefined Instruction Usage Fault:  {}\n\
-             \tInvalid State Usage Fault:          {}\n\
-             \tInvalid PC Load Usage Fault:        {}\n\
-             \tNo Coprocessor Usage Fault:         {}\n\
-             \tUnaligned Access Usage Fault:       {}\n\
-             \tDivide By Zero:                     {}\n\
-             \tBus Fault on Vector Table Read:     {}\n\
-             \tForced Hard Fault:                  {}\n\
-             \tFaulting Memory Address: (valid: {}) {:#010X}\n\
-             \tBus Fault Address:       (valid: {}) {:#010X}\n\
-             ",
+             {}",
             mode_str,
             env!("TOCK_KERNEL_VERSION"),
             stacked_r0,
@@ -321,31 +439,7 @@ pub unsafe extern "C" fn hard_fault_handler() {
             (_estack as *const ()) as u32,
             (&_ezero as *const u32) as u32,
             shcsr,
-            cfsr,
-            hfsr,
-            iaccviol,
-            daccviol,
-            munstkerr,
-            mstkerr,
-            mlsperr,
-            ibuserr,
-            preciserr,
-            impreciserr,
-            unstkerr,
-            stkerr,
-            lsperr,
-            undefinstr,
-            invstate,
-            invpc,
-            nocp,
-            unaligned,
-            divbysero,
-            vecttbl,
-            forced,
-            mmfarvalid,
-            mmfar,
-            bfarvalid,
-            bfar
+            fault_status
         );
     } else {
         // hard fault occurred in an app, not the kernel. The app should be
@@ -376,8 +470,8 @@ pub unsafe extern "C" fn hard_fault_handler() {
               mov r0, #0
               msr CONTROL, r0
 
-              movw LR, #0xFFF9
-              movt LR, #0xFFFF"
+              ldr r0, =KERNEL_EXC_RETURN
+              ldr lr, [r0, #0]"
         );
     }
 }
@@ -163,7 +163,7 @@ impl kernel::mpu::MPU for MPU {
         //    subregions, as long as the memory region's base address is aligned
         //    to 1/8th of a larger region size.
 
-        if start % len == 0 {
+        if len.is_power_of_two() && start % len == 0 {
             // Memory base aligned to memory size - straight forward case
             let region_len = PowerOfTwo::floor(len as u32);
             if region_len.exp::<u32>() < 5 {
@@ -183,47 +183,45 @@ impl kernel::mpu::MPU for MPU {
                 )
             })
         } else {
-            // Memory base not aligned to memory size
-
-            // Which (power-of-two) subregion size would align with the base
-            // address?
-            //
-            // We find this by taking smallest binary substring of the base
-            // address with exactly one bit:
-            //
-            //      1 << (start.trailing_zeros())
-            let subregion_size = {
-                let tz = start.trailing_zeros();
-                // `start` should never be 0 because of that's taken care of by
-                // the previous branch, but in case it is, do the right thing
-                // anyway.
-                if tz < 32 {
-                    (1 as usize) << tz
-                } else {
-                    0
+            // Memory base not aligned to memory size, or the memory size is
+            // not a power of two.
+
+            // Find the smallest region whose subregions can exactly cover
+            // the memory. Smaller regions waste less of the address space
+            // around the memory, and let the memory size be any multiple of
+            // a smaller subregion size. Subregions are only supported for
+            // region sizes of 128 bytes and up, and the first candidate is
+            // the smallest region at least as large as the memory.
+            let min_region_exp = PowerOfTwo::ceiling(len as u32).exp::<u32>().max(7);
+            let (subregion_size, region_size, region_start) = {
+                let mut found = None;
+                for exp in min_region_exp..32 {
+                    let region_size = (1 as usize) << exp;
+                    let subregion_size = region_size / 8;
+                    if start % subregion_size != 0 {
+                        // `start` is not aligned to this subregion size, and
+                        // so it cannot be aligned to any larger one either.
+                        break;
+                    }
+                    // The region base is the nearest address below `start`
+                    // that aligns with the region size.
+                    let region_start = start - (start % region_size);
+                    // The space left in the region after `start` must be at
+                    // least as large as the memory we want to reference, and
+                    // there must be some integer X such that
+                    // subregion_size * X == len so none of `len` is left over
+                    // when we take the max_subregion.
+                    if region_size - (start - region_start) >= len && len % subregion_size == 0 {
+                        found = Some((subregion_size, region_size, region_start));
+                        break;
+                    }
+                }
+                match found {
+                    Some(found) => found,
+                    None => return None,
                 }
             };
 
-            // Once we have a subregion size, we get a region size by
-            // multiplying it by the number of subregions per region.
-            let region_size = subregion_size * 8;
-            // Finally, we calculate the region base by finding the nearest
-            // address below `start` that aligns with the region size.
-            let region_start = start - (start % region_size);
-
-            if region_size + region_start - start < len {
-                // Sanity check that the amount left over space in the region
-                // after `start` is at least as large as the memory region we
-                // want to reference.
-                return None;
-            }
-            if len % subregion_size != 0 {
-                // Sanity check that there is some integer X such that
-                // subregion_size * X == len so none of `len` is left over when
-                // we take the max_subregion.
-                return None;
-            }
-
             // The index of the first subregion to activate is the number of
             // regions between `region_start` (MPU) and `start` (memory).
             let min_subregion = (start - region_start) / subregion_size;
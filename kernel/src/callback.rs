@@ -4,18 +4,25 @@ use core::fmt;
 use core::ptr::NonNull;
 
 use process;
+use returncode::ReturnCode;
 use sched::Kernel;
 
 /// Userspace app identifier.
+///
+/// Besides the process's slot in the kernel's processes array, an `AppId`
+/// records the generation of the process in that slot when it was created.
+/// If the process is restarted, or its slot is reused for another process,
+/// the `AppId` goes stale and no longer refers to any process.
 #[derive(Clone, Copy)]
 pub struct AppId {
     crate kernel: &'static Kernel,
     idx: usize,
+    generation: usize,
 }
 
 impl PartialEq for AppId {
     fn eq(&self, other: &AppId) -> bool {
-        self.idx == other.idx
+        self.idx == other.idx && self.generation == other.generation
     }
 }
 
@@ -28,10 +35,12 @@ impl fmt::Debug for AppId {
 }
 
 impl AppId {
+    /// Create an `AppId` for the process currently in slot `idx`.
     crate fn new(kernel: &'static Kernel, idx: usize) -> AppId {
         AppId {
             kernel: kernel,
             idx: idx,
+            generation: kernel.process_map_or(0, idx, |process| process.generation()),
         }
     }
 
@@ -39,13 +48,39 @@ impl AppId {
         self.idx
     }
 
+    /// The kernel that runs the process this `AppId` refers to.
+    pub fn kernel(&self) -> &'static Kernel {
+        self.kernel
+    }
+
+    crate fn generation(&self) -> usize {
+        self.generation
+    }
+
+    /// The package name of the process, e.g. for capsules to log which app
+    /// they are handling. This works whatever state the process is in,
+    /// including faulted. An `AppId` that no longer refers to a process has
+    /// an empty name.
+    pub fn process_name(&self) -> &'static str {
+        self.kernel
+            .with_process(*self, "", |process| process.package_name)
+    }
+
     pub fn get_editable_flash_range(&self) -> (usize, usize) {
-        self.kernel.process_map_or((0, 0), self.idx, |process| {
+        self.kernel.with_process(*self, (0, 0), |process| {
             let start = process.flash_non_protected_start() as usize;
             let end = process.flash_end() as usize;
             (start, end)
         })
     }
+
+    /// Take the multi-word value this app most recently passed to the kernel
+    /// with memop, copying it into `buf`. See
+    /// `Process::take_committed_words()`.
+    pub fn take_committed_words(&self, buf: &mut [usize]) -> Option<usize> {
+        self.kernel
+            .with_process(*self, None, |process| process.take_committed_words(buf))
+    }
 }
 
 /// Wrapper around a function pointer.
@@ -68,14 +103,89 @@ impl Callback {
     pub fn schedule(&mut self, r0: usize, r1: usize, r2: usize) -> bool {
         self.app_id
             .kernel
-            .process_map_or(false, self.app_id.idx(), |process| {
-                process.schedule(process::FunctionCall {
-                    r0: r0,
-                    r1: r1,
-                    r2: r2,
-                    r3: self.appdata,
-                    pc: self.fn_ptr.as_ptr() as usize,
-                })
+            .with_process(self.app_id, false, |process| {
+                process.schedule(process::FunctionCall::new(
+                    self.fn_ptr.as_ptr() as usize,
+                    r0,
+                    r1,
+                    r2,
+                    self.appdata,
+                ))
             })
     }
+
+    /// Like `schedule()`, but the callback goes on the process's short
+    /// priority queue, which runs before its other callbacks and is not
+    /// filled by them. Use this only for callbacks that must not be dropped,
+    /// such as error notifications.
+    pub fn schedule_high_priority(&mut self, r0: usize, r1: usize, r2: usize) -> bool {
+        self.app_id
+            .kernel
+            .with_process(self.app_id, false, |process| {
+                process.schedule_high_priority(process::FunctionCall::new(
+                    self.fn_ptr.as_ptr() as usize,
+                    r0,
+                    r1,
+                    r2,
+                    self.appdata,
+                ))
+            })
+    }
+}
+
+/// A small table of callbacks for drivers that let an app subscribe several
+/// callbacks of the same kind, e.g. one per GPIO pin. Each callback is keyed
+/// by its index in the table, typically taken from the subscribe number.
+///
+/// The table is backed by an array, so that it can be stored in a grant:
+///
+/// ```ignore
+/// struct App {
+///     callbacks: CallbackTable<[Option<Callback>; 4]>,
+/// }
+/// ```
+#[derive(Default)]
+pub struct CallbackTable<A: AsRef<[Option<Callback>]> + AsMut<[Option<Callback>]>> {
+    callbacks: A,
+}
+
+impl<A: AsRef<[Option<Callback>]> + AsMut<[Option<Callback>]>> CallbackTable<A> {
+    pub fn new(callbacks: A) -> CallbackTable<A> {
+        CallbackTable {
+            callbacks: callbacks,
+        }
+    }
+
+    /// The number of callbacks the table can hold.
+    pub fn capacity(&self) -> usize {
+        self.callbacks.as_ref().len()
+    }
+
+    /// Store `callback` at `index`, replacing any callback already there.
+    /// Passing `None` removes the callback. Returns `EINVAL` if `index` is
+    /// outside the table.
+    pub fn set(&mut self, index: usize, callback: Option<Callback>) -> ReturnCode {
+        match self.callbacks.as_mut().get_mut(index) {
+            Some(slot) => {
+                *slot = callback;
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// The callback stored at `index`, if any.
+    pub fn get(&self, index: usize) -> Option<Callback> {
+        self.callbacks.as_ref().get(index).and_then(|cb| *cb)
+    }
+
+    /// Schedule the callback stored at `index`, as with
+    /// `Callback::schedule()`. Returns `false` if there is no callback at
+    /// `index` or it could not be scheduled.
+    pub fn schedule(&mut self, index: usize, r0: usize, r1: usize, r2: usize) -> bool {
+        match self.callbacks.as_mut().get_mut(index) {
+            Some(Some(callback)) => callback.schedule(r0, r1, r2),
+            _ => false,
+        }
+    }
 }
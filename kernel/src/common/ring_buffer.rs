@@ -16,6 +16,26 @@ impl<T: Copy> RingBuffer<'a, T> {
             ring: ring,
         }
     }
+
+    /// How many elements the ring buffer can hold. One slot of the
+    /// underlying buffer is always left empty to tell full from empty.
+    pub fn capacity(&self) -> usize {
+        self.ring.len() - 1
+    }
+
+    /// Whether the head and tail indices lie within the buffer. This is
+    /// always the case unless the memory holding the ring buffer was
+    /// corrupted.
+    pub fn indices_valid(&self) -> bool {
+        self.head < self.ring.len() && self.tail < self.ring.len()
+    }
+
+    /// Iterate over the elements in the ring buffer, oldest first, without
+    /// removing them.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let len = queue::Queue::len(self);
+        (0..len).map(move |i| &self.ring[(self.head + i) % self.ring.len()])
+    }
 }
 
 impl<T: Copy> queue::Queue<T> for RingBuffer<'a, T> {
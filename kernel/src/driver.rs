@@ -41,6 +41,7 @@
 use callback::{AppId, Callback};
 use mem::{AppSlice, Shared};
 use returncode::ReturnCode;
+use syscall::CommandReturn;
 
 /// `Driver`s implement the three driver-specific system calls: `subscribe`,
 /// `command` and `allow`.
@@ -96,12 +97,49 @@ pub trait Driver {
         ReturnCode::ENOSUPPORT
     }
 
+    /// `command_return` is what the kernel calls to handle the `command`
+    /// syscall. By default it calls `command` and passes back its return code.
+    /// Drivers that want to pass back a value alongside a success code, rather
+    /// than encoding it in `ReturnCode::SuccessWithValue`, can implement this
+    /// instead, e.g. returning `CommandReturn::success_u32(value)`.
+    fn command_return(
+        &self,
+        minor_num: usize,
+        r2: usize,
+        r3: usize,
+        caller_id: AppId,
+    ) -> CommandReturn {
+        self.command(minor_num, r2, r3, caller_id).into()
+    }
+
+    /// `command_extended` is `command_return` with a third data argument,
+    /// `r4`, which the process passes in register `r4`. The kernel calls this
+    /// for every `command` syscall, and by default it ignores `r4` and calls
+    /// `command_return`. Only drivers whose commands take a third argument
+    /// need to implement it; since processes that do not use it leave
+    /// arbitrary values in `r4`, other drivers must not read it.
+    #[allow(unused_variables)]
+    fn command_extended(
+        &self,
+        minor_num: usize,
+        r2: usize,
+        r3: usize,
+        r4: usize,
+        caller_id: AppId,
+    ) -> CommandReturn {
+        self.command_return(minor_num, r2, r3, caller_id)
+    }
+
     /// `allow` lets an application give the driver access to a buffer in the
     /// application's memory. This returns `ENOSUPPORT` if not used.
     ///
     /// The buffer is __shared__ between the application and driver, meaning the
     /// driver should not rely on the contents of the buffer to remain
     /// unchanged.
+    ///
+    /// The application unshares a buffer by allowing a null pointer with the
+    /// same `minor_num`, in which case `slice` is `None`. Any `AppSlice` the
+    /// driver still holds for the old buffer is empty from then on.
     #[allow(unused_variables)]
     fn allow(
         &self,
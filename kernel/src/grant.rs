@@ -1,12 +1,13 @@
 //! Data structure to store a list of userspace applications.
 
 use core::marker::PhantomData;
-use core::mem::size_of;
+use core::mem::{self, size_of};
 use core::ops::{Deref, DerefMut};
 use core::ptr::Unique;
 
 use callback::AppId;
-use process::Error;
+use process::{Error, Process};
+use returncode::ReturnCode;
 use sched::Kernel;
 
 pub struct Grant<T: Default> {
@@ -33,6 +34,17 @@ impl<T> AppliedGrant<T> {
     }
 }
 
+/// Allocate grant `grant_num`, of type `T`, for `process` if it does not have
+/// it yet. `Kernel::create_grant()` records this function for each grant, so
+/// that `Kernel::preallocate_grants()` can allocate grants without knowing
+/// their types.
+crate unsafe fn allocate_grant<T: Default>(
+    process: &Process,
+    grant_num: usize,
+) -> Result<(), Error> {
+    process.grant_for_or_alloc::<T>(grant_num).map(|_| ())
+}
+
 pub struct Allocator {
     appid: AppId,
 }
@@ -55,15 +67,21 @@ impl<T: ?Sized> Owned<T> {
     }
 }
 
+impl<T> Owned<T> {
+    /// Put `new` in place of the owned value and return the old one. The
+    /// memory stays allocated to the app, and neither value is dropped here.
+    pub fn replace(&mut self, new: T) -> T {
+        mem::replace(&mut **self, new)
+    }
+}
+
 impl<T: ?Sized> Drop for Owned<T> {
     fn drop(&mut self) {
         unsafe {
             let data = self.data.as_ptr() as *mut u8;
-            self.appid
-                .kernel
-                .process_map_or((), self.appid.idx(), |process| {
-                    process.free(data);
-                });
+            self.appid.kernel.with_process(self.appid, (), |process| {
+                process.free(data);
+            });
         }
     }
 }
@@ -86,14 +104,16 @@ impl Allocator {
         unsafe {
             self.appid
                 .kernel
-                .process_map_or(Err(Error::NoSuchApp), self.appid.idx(), |process| {
-                    process
-                        .alloc(size_of::<T>())
-                        .map_or(Err(Error::OutOfMemory), |arr| {
-                            let mut owned = Owned::new(arr.as_mut_ptr() as *mut T, self.appid);
-                            *owned = data;
-                            Ok(owned)
-                        })
+                .with_process(self.appid, Err(Error::NoSuchApp), |process| {
+                    // A faulted process's memory is about to be reset.
+                    if !process.is_active() {
+                        return Err(Error::NoSuchApp);
+                    }
+                    process.alloc_result(size_of::<T>()).map(|arr| {
+                        let mut owned = Owned::new(arr.as_mut_ptr() as *mut T, self.appid);
+                        *owned = data;
+                        owned
+                    })
                 })
         }
     }
@@ -112,6 +132,8 @@ impl<T: 'a + ?Sized> Borrowed<'a, T> {
         }
     }
 
+    /// The process this grant region belongs to. For a `Borrowed` passed to
+    /// the closure of `Grant::enter`, this is the `appid` given to `enter`.
     pub fn appid(&self) -> AppId {
         self.appid
     }
@@ -141,7 +163,7 @@ impl<T: Default> Grant<T> {
 
     pub fn grant(&self, appid: AppId) -> Option<AppliedGrant<T>> {
         unsafe {
-            appid.kernel.process_map_or(None, appid.idx(), |process| {
+            appid.kernel.with_process(appid, None, |process| {
                 let cntr = process.grant_for::<T>(self.grant_num);
                 if cntr.is_null() {
                     None
@@ -156,6 +178,34 @@ impl<T: Default> Grant<T> {
         }
     }
 
+    /// Whether the process `appid` has a grant region for this grant. Unlike
+    /// `enter`, this never allocates the region.
+    pub fn is_allocated(&self, appid: AppId) -> bool {
+        unsafe {
+            appid.kernel.with_process(appid, false, |process| {
+                !process.grant_for::<T>(self.grant_num).is_null()
+            })
+        }
+    }
+
+    /// Allocate the grant region of the process `appid` now if it does not
+    /// have one yet, so that a later `enter` cannot fail for lack of memory.
+    pub fn ensure_allocated(&self, appid: AppId) -> Result<(), Error> {
+        unsafe {
+            appid
+                .kernel
+                .with_process(appid, Err(Error::NoSuchApp), |process| {
+                    if !process.is_active() {
+                        return Err(Error::NoSuchApp);
+                    }
+                    allocate_grant::<T>(process, self.grant_num)
+                })
+        }
+    }
+
+    /// Run `fun` on the grant region of the process `appid`, allocating the
+    /// region first if the process does not have one yet. The closure can get
+    /// the id of the process it is running for from `Borrowed::appid()`.
     pub fn enter<F, R>(&self, appid: AppId, fun: F) -> Result<R, Error>
     where
         F: FnOnce(&mut Borrowed<T>, &mut Allocator) -> R,
@@ -164,20 +214,35 @@ impl<T: Default> Grant<T> {
         unsafe {
             appid
                 .kernel
-                .process_map_or(Err(Error::NoSuchApp), appid.idx(), |process| {
-                    process.grant_for_or_alloc::<T>(self.grant_num).map_or(
-                        Err(Error::OutOfMemory),
-                        move |root_ptr| {
+                .with_process(appid, Err(Error::NoSuchApp), |process| {
+                    // Do not touch the grant of a process that faulted, as
+                    // its memory is about to be reset.
+                    if !process.is_active() {
+                        return Err(Error::NoSuchApp);
+                    }
+                    process
+                        .grant_for_or_alloc::<T>(self.grant_num)
+                        .map(move |root_ptr| {
                             let mut root = Borrowed::new(&mut *root_ptr, appid);
                             let mut allocator = Allocator { appid: appid };
-                            let res = fun(&mut root, &mut allocator);
-                            Ok(res)
-                        },
-                    )
+                            fun(&mut root, &mut allocator)
+                        })
                 })
         }
     }
 
+    /// Like `enter`, but for closures that produce a `ReturnCode`, as most
+    /// `command` and `allow` handlers do. If the grant region cannot be
+    /// entered the error is converted to a `ReturnCode` (for example `ENOMEM`
+    /// if the region could not be allocated), so the result can be handed
+    /// straight back to the process.
+    pub fn enter_return_code<F>(&self, appid: AppId, fun: F) -> ReturnCode
+    where
+        F: FnOnce(&mut Borrowed<T>, &mut Allocator) -> ReturnCode,
+    {
+        self.enter(appid, fun).unwrap_or_else(|err| err.into())
+    }
+
     pub fn each<F>(&self, fun: F)
     where
         F: Fn(&mut Owned<T>),
@@ -192,6 +257,26 @@ impl<T: Default> Grant<T> {
             });
     }
 
+    /// Like `each`, but stops visiting processes as soon as `fun` returns
+    /// `true`. This is useful for finding a particular app without walking
+    /// every process slot.
+    pub fn each_until<F>(&self, fun: F)
+    where
+        F: Fn(&mut Owned<T>) -> bool,
+    {
+        self.kernel
+            .process_each_enumerate_stop(|app_id, process| unsafe {
+                let root_ptr = process.grant_for::<T>(self.grant_num);
+                if !root_ptr.is_null() {
+                    let mut root = Owned::new(root_ptr, AppId::new(self.kernel, app_id));
+                    if fun(&mut root) {
+                        return ReturnCode::SUCCESS;
+                    }
+                }
+                ReturnCode::FAIL
+            });
+    }
+
     pub fn iter(&self) -> Iter<T> {
         Iter {
             grant: self,
@@ -167,7 +167,8 @@ impl Driver for IPC {
     /// call. The contents of the slice should be the string name of the IPC
     /// service. If this mechanism can find that service, allow will return
     /// an ID that can be used to notify that service. Otherwise an error will
-    /// be returned.
+    /// be returned. If several processes have that name, the one in the lowest
+    /// process slot is found.
     ///
     /// If allow is called with target_id >= 1, it is a share command where the
     /// application is explicitly sharing a slice with an IPC service (as
@@ -37,7 +37,7 @@ mod sched;
 mod syscall;
 mod tbfheader;
 
-pub use callback::{AppId, Callback};
+pub use callback::{AppId, Callback, CallbackTable};
 pub use driver::Driver;
 pub use grant::Grant;
 pub use mem::{AppPtr, AppSlice, Private, Shared};
@@ -45,7 +45,12 @@ pub use platform::systick::SysTick;
 pub use platform::{mpu, Chip, Platform};
 pub use platform::{ClockInterface, NoClockControl, NO_CLOCK_CONTROL};
 pub use returncode::ReturnCode;
-pub use sched::Kernel;
+pub use sched::{EventLogDrain, Kernel, KernelEvent, KernelEventKind, SchedulingPolicy};
+pub use syscall::{CommandReturn, Syscall, SyscallLogger};
+
+/// The version of the kernel, as `(major, minor)`. Apps whose TBF header
+/// requires a newer kernel are not loaded.
+pub const KERNEL_VERSION: (u16, u16) = (1, 2);
 
 // These symbols must be exported for the arch crate to access them.
 pub use process::APP_FAULT;
@@ -56,5 +61,10 @@ pub use process::SYSCALL_FIRED;
 // functions and types are used by board files to setup the platform and setup
 // processes.
 pub mod procs {
-    pub use process::{load_processes, FaultResponse, Process};
+    #[cfg(any(test, feature = "fault_injection"))]
+    pub use process::FaultKind;
+    pub use process::{load_processes, load_processes_with_allocation, MemoryAllocation};
+    pub use process::{AppVerifier, FunctionCall, IPCType, Task};
+    pub use process::{Error, FaultResponse, FaultStatus, MemoryMap, NotRunnableReason};
+    pub use process::{Process, ProcessObserver, ResourcePressure, RestartCounterStore};
 }
@@ -1,11 +1,13 @@
 //! Data structure for passing application memory to the kernel.
 
 use core::marker::PhantomData;
+use core::mem;
 use core::ops::{Deref, DerefMut};
 use core::ptr::Unique;
 use core::slice;
 
 use callback::AppId;
+use process::UnshareTable;
 
 #[derive(Debug)]
 pub struct Private;
@@ -46,41 +48,139 @@ impl<L, T> Drop for AppPtr<L, T> {
     fn drop(&mut self) {
         self.process
             .kernel
-            .process_map_or((), self.process.idx(), |process| unsafe {
+            .with_process(self.process, (), |process| unsafe {
                 process.free(self.ptr.as_mut())
             })
     }
 }
 
+/// A buffer a process shared with a driver through ALLOW.
+///
+/// The slice remembers which ALLOW call created it. Once the process unshares
+/// the buffer, by allowing a null pointer to the same driver and allow
+/// number, or once the process restarts, the slice becomes empty: `len()` is
+/// 0 and `as_ref()` and `as_mut()` return empty slices. Drivers can keep the
+/// slice around, but can no longer reach memory the process has taken back.
 pub struct AppSlice<L, T> {
     ptr: AppPtr<L, T>,
     len: usize,
+    /// Where the process records unshares of this buffer.
+    unshare_bucket: usize,
+    allow_count: usize,
 }
 
 impl<L, T> AppSlice<L, T> {
-    crate fn new(ptr: *mut T, len: usize, appid: AppId) -> AppSlice<L, T> {
+    crate fn new(
+        ptr: *mut T,
+        len: usize,
+        appid: AppId,
+        driver_num: usize,
+        allow_num: usize,
+        allow_count: usize,
+    ) -> AppSlice<L, T> {
+        AppSlice::with_bucket(
+            ptr,
+            len,
+            appid,
+            UnshareTable::bucket(driver_num, allow_num),
+            allow_count,
+        )
+    }
+
+    fn with_bucket(
+        ptr: *mut T,
+        len: usize,
+        appid: AppId,
+        unshare_bucket: usize,
+        allow_count: usize,
+    ) -> AppSlice<L, T> {
         unsafe {
             AppSlice {
                 ptr: AppPtr::new(ptr, appid),
                 len: len,
+                unshare_bucket: unshare_bucket,
+                allow_count: allow_count,
             }
         }
     }
 
+    /// Whether the process still shares this buffer with the kernel. Each
+    /// accessor checks this once, not per element.
+    fn is_shared(&self) -> bool {
+        let appid = self.ptr.process;
+        appid.kernel.with_process(appid, false, |process| {
+            process.allow_valid(self.unshare_bucket, self.allow_count)
+        })
+    }
+
+    /// The length of the slice, or 0 if the process has since unshared it.
     pub fn len(&self) -> usize {
-        self.len
+        if self.is_shared() {
+            self.len
+        } else {
+            0
+        }
+    }
+
+    /// Whether the slice has a length of zero. A process can allow a
+    /// zero-length buffer to stop sharing memory with a driver, so drivers
+    /// should treat an empty slice like no slice at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     pub fn ptr(&self) -> *const T {
         self.ptr.ptr.as_ptr()
     }
 
+    /// Split this slice into two at index `mid`, without copying. The first
+    /// slice contains `[0, mid)` and the second `[mid, len)`.
+    ///
+    /// Both halves refer to memory of the same process and to the same region
+    /// it shared with the kernel; they are simply narrower views of it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mid > len`.
+    pub fn split_at(self, mid: usize) -> (AppSlice<L, T>, AppSlice<L, T>) {
+        assert!(mid <= self.len);
+        let ptr = self.ptr.ptr.as_ptr();
+        let len = self.len;
+        let appid = self.ptr.process;
+        let (unshare_bucket, allow_count) = (self.unshare_bucket, self.allow_count);
+        // Both halves take over ownership of the memory from `self`, so it
+        // must not be dropped (and freed) here.
+        mem::forget(self);
+        unsafe {
+            (
+                AppSlice::with_bucket(ptr, mid, appid, unshare_bucket, allow_count),
+                AppSlice::with_bucket(
+                    ptr.offset(mid as isize),
+                    len - mid,
+                    appid,
+                    unshare_bucket,
+                    allow_count,
+                ),
+            )
+        }
+    }
+
+    /// The `len` elements starting at `offset`, or `None` if they do not all
+    /// lie within the slice.
+    pub fn get_mut(&mut self, offset: usize, len: usize) -> Option<&mut [T]> {
+        let slice = self.as_mut();
+        match offset.checked_add(len) {
+            Some(end) if end <= slice.len() => Some(&mut slice[offset..end]),
+            _ => None,
+        }
+    }
+
     crate unsafe fn expose_to(&self, appid: AppId) -> bool {
         if appid.idx() != self.ptr.process.idx() {
             self.ptr
                 .process
                 .kernel
-                .process_map_or(false, appid.idx(), |process| {
+                .with_process(appid, false, |process| {
                     process.add_mpu_region(self.ptr() as *const u8, self.len() as u32)
                 })
         } else {
@@ -103,16 +203,54 @@ impl<L, T> AppSlice<L, T> {
     pub fn chunks_mut(&mut self, size: usize) -> slice::ChunksMut<T> {
         self.as_mut().chunks_mut(size)
     }
+
+    /// Split the slice into chunks of exactly `size` elements and the
+    /// remainder that does not fill a whole chunk, e.g. to frame data into
+    /// fixed-size packets without checking the length of every chunk.
+    /// Returns `None` if `size` is 0.
+    ///
+    /// This is not std's `as_chunks()`, which returns arrays of a const
+    /// generic length, as the toolchain the kernel is built with does not
+    /// support const generics. Every chunk is a slice of `size` elements.
+    pub fn chunks_exact_with_rest(&self, size: usize) -> Option<(slice::Chunks<T>, &[T])> {
+        if size == 0 {
+            return None;
+        }
+        let slice = self.as_ref();
+        let (whole, rest) = slice.split_at(slice.len() - slice.len() % size);
+        Some((whole.chunks(size), rest))
+    }
+}
+
+impl<L, T: Copy> AppSlice<L, T> {
+    /// Copy as much of `src` into the start of this slice as fits, and return
+    /// the number of elements copied, i.e. the smaller of the two lengths.
+    pub fn copy_from(&mut self, src: &[T]) -> usize {
+        let dst = self.as_mut();
+        let n = dst.len().min(src.len());
+        dst[..n].copy_from_slice(&src[..n]);
+        n
+    }
+
+    /// Copy as much of the start of this slice into `dst` as fits, and return
+    /// the number of elements copied, i.e. the smaller of the two lengths.
+    pub fn copy_to(&self, dst: &mut [T]) -> usize {
+        let src = self.as_ref();
+        let n = src.len().min(dst.len());
+        dst[..n].copy_from_slice(&src[..n]);
+        n
+    }
 }
 
 impl<L, T> AsRef<[T]> for AppSlice<L, T> {
     fn as_ref(&self) -> &[T] {
-        unsafe { slice::from_raw_parts(self.ptr.ptr.as_ref(), self.len) }
+        unsafe { slice::from_raw_parts(self.ptr.ptr.as_ref(), self.len()) }
     }
 }
 
 impl<L, T> AsMut<[T]> for AppSlice<L, T> {
     fn as_mut(&mut self) -> &mut [T] {
-        unsafe { slice::from_raw_parts_mut(self.ptr.ptr.as_mut(), self.len) }
+        let len = self.len();
+        unsafe { slice::from_raw_parts_mut(self.ptr.ptr.as_mut(), len) }
     }
 }
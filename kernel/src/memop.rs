@@ -31,11 +31,23 @@ use returncode::ReturnCode;
 /// - `10`: Specify where the start of the app stack is. This tells the kernel
 ///   where the app has put the start of its stack. This is not strictly
 ///   necessary for correct operation, but allows for better debugging if the
-///   app crashes.
+///   app crashes. Returns EINVAL if the address is not in the app's memory.
 /// - `11`: Specify where the start of the app heap is. This tells the kernel
 ///   where the app has put the start of its heap. This is not strictly
 ///   necessary for correct operation, but allows for better debugging if the
-///   app crashes.
+///   app crashes. Returns EINVAL if the address is not in the app's memory.
+/// - `12`: Stage the word in r1 as the next part of a multi-word value being
+///   passed to the kernel. Returns ESIZE if the staging buffer is full.
+/// - `13`: Commit the staged words so the kernel can use them. Returns the
+///   number of words committed, or EINVAL if no words were staged.
+/// - `14`: Discard any staged words that have not been committed.
+/// - `15`: Ask the scheduler to run the process with the IPC id in r1 next, if
+///   it is runnable. Returns EINVAL if there is no such process.
+/// - `16`: Set the process's deadline to r1, for the earliest-deadline-first
+///   scheduling policy.
+/// - `17`: Clear the process's deadline.
+/// - `18`: Set the process's priority to r1, for the priority scheduling
+///   policy. Returns EINVAL if r1 is greater than 255.
 crate fn memop(process: &Process) -> ReturnCode {
     let op_type = process.r0();
     let r1 = process.r1();
@@ -100,17 +112,69 @@ crate fn memop(process: &Process) -> ReturnCode {
         }
 
         // Op Type 10: Specify where the start of the app stack is.
-        10 => {
-            process.update_stack_start_pointer(r1 as *const u8);
+        10 => process
+            .update_stack_start_pointer(r1 as *const u8)
+            .map(|_| ReturnCode::SUCCESS)
+            .unwrap_or_else(|err| err.into()),
+
+        // Op Type 11: Specify where the start of the app heap is.
+        11 => process
+            .update_heap_start_pointer(r1 as *const u8)
+            .map(|_| ReturnCode::SUCCESS)
+            .unwrap_or_else(|err| err.into()),
+
+        // Op Type 12: Stage a word of a multi-word value.
+        12 => {
+            if process.stage_word(r1) {
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::ESIZE
+            }
+        }
+
+        // Op Type 13: Commit the staged words.
+        13 => process
+            .commit_staged_words()
+            .map(|len| ReturnCode::SuccessWithValue { value: len })
+            .unwrap_or(ReturnCode::EINVAL),
+
+        // Op Type 14: Discard the staged words.
+        14 => {
+            process.discard_staged_words();
             ReturnCode::SUCCESS
         }
 
-        // Op Type 11: Specify where the start of the app heap is.
-        11 => {
-            process.update_heap_start_pointer(r1 as *const u8);
+        // Op Type 15: Hint which process to run next.
+        15 => {
+            if process.hint_next_process(r1) {
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::EINVAL
+            }
+        }
+
+        // Op Type 16: Set the process's deadline.
+        16 => {
+            process.set_deadline(Some(r1 as u32));
+            ReturnCode::SUCCESS
+        }
+
+        // Op Type 17: Clear the process's deadline.
+        17 => {
+            process.set_deadline(None);
             ReturnCode::SUCCESS
         }
 
+        // Op Type 18: Set the process's priority.
+        18 => {
+            if r1 <= u8::max_value() as usize {
+                process.set_priority(r1 as u8);
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::EINVAL
+            }
+        }
+
         _ => ReturnCode::ENOSUPPORT,
     }
 }
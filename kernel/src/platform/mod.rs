@@ -24,6 +24,15 @@ pub trait Chip {
     fn mpu(&self) -> &Self::MPU;
     fn systick(&self) -> &Self::SysTick;
     fn sleep(&self);
+
+    /// Sleep until an interrupt occurs or `deadline` is reached, whichever
+    /// is first. `deadline` is in the units of the clock used for software
+    /// alarms on this chip. Chips that cannot wake on a deadline keep the
+    /// default, which sleeps until the next interrupt.
+    fn sleep_until(&self, _deadline: u32) {
+        self.sleep();
+    }
+
     unsafe fn atomic<F, R>(&self, f: F) -> R
     where
         F: FnOnce() -> R;
@@ -6,14 +6,14 @@ use common::{Queue, RingBuffer};
 use core::cell::Cell;
 use core::fmt::Write;
 use core::ptr::{read_volatile, write, write_volatile};
-use core::{mem, ptr, slice, str};
+use core::{fmt, mem, ptr, slice, str};
 
-use common::cells::MapCell;
+use common::cells::{MapCell, NumericCellExt, TakeCell};
 use common::math;
 use platform::mpu;
 use returncode::ReturnCode;
 use sched::Kernel;
-use syscall::Syscall;
+use syscall::{CommandReturn, Syscall};
 use tbfheader;
 
 /// This is used in the hardfault handler.
@@ -34,7 +34,9 @@ static mut SCB_REGISTERS: [u32; 5] = [0; 5];
 
 #[allow(improper_ctypes)]
 extern "C" {
-    crate fn switch_to_user(user_stack: *const u8, process_regs: &[usize; 8]) -> *mut u8;
+    crate fn switch_to_user(user_stack: *const u8, process_regs: &StoredRegs) -> *mut u8;
+    #[cfg(feature = "armv8m")]
+    crate fn set_process_stack_limit(limit: *const u8);
 }
 
 /// Helper function to load processes from flash into an array of active
@@ -47,12 +49,40 @@ extern "C" {
 /// number of processes are created, with process structures placed in the
 /// provided array. How process faults are handled by the kernel is also
 /// selected.
+///
+/// An app that cannot be loaded (for example because it requests more memory
+/// than is left, or its entry point is invalid) is skipped with a debug message
+/// and loading continues with the next app in flash. Apps that share a package
+/// name with an app loaded before them are loaded, with a debug message.
+///
+/// Process memory is allocated bottom-up from `app_memory`. Use
+/// `load_processes_with_allocation()` to choose a different strategy.
 pub unsafe fn load_processes(
     kernel: &'static Kernel,
     start_of_flash: *const u8,
     app_memory: &mut [u8],
     procs: &mut [Option<&Process<'static>>],
     fault_response: FaultResponse,
+) {
+    load_processes_with_allocation(
+        kernel,
+        start_of_flash,
+        app_memory,
+        procs,
+        fault_response,
+        MemoryAllocation::BottomUp,
+    );
+}
+
+/// Same as `load_processes()`, but with a selectable strategy for how each
+/// process's RAM is placed within `app_memory`.
+pub unsafe fn load_processes_with_allocation(
+    kernel: &'static Kernel,
+    start_of_flash: *const u8,
+    app_memory: &mut [u8],
+    procs: &mut [Option<&Process<'static>>],
+    fault_response: FaultResponse,
+    allocation: MemoryAllocation,
 ) {
     let mut apps_in_flash_ptr = start_of_flash;
     let mut app_memory_ptr = app_memory.as_mut_ptr();
@@ -64,7 +94,22 @@ pub unsafe fn load_processes(
             app_memory_ptr,
             app_memory_size,
             fault_response,
+            allocation,
         );
+        let process = process.ok();
+
+        // `create` should never report using more memory than it was given,
+        // or flash beyond the end of the address space, but a malformed
+        // header must not make the bookkeeping below wrap around.
+        let next_app_address = (apps_in_flash_ptr as usize).checked_add(flash_offset);
+        if memory_offset > app_memory_size || next_app_address.is_none() {
+            debug!(
+                "Process {} reported using {:#x} bytes of flash and {:#x} bytes of RAM, \
+                 with {:#x} bytes of RAM left. Not loading any more processes.",
+                i, flash_offset, memory_offset, app_memory_size
+            );
+            break;
+        }
 
         if process.is_none() {
             // We did not get a valid process, but we may have gotten a disabled
@@ -75,28 +120,79 @@ pub unsafe fn load_processes(
                 break;
             }
         } else {
+            // Nothing stops two apps from having the same package name, but
+            // IPC service discovery only ever finds the one in the lowest
+            // slot, so point this out.
+            process.map(|process| {
+                let name = process.package_name;
+                let duplicate = procs[..i]
+                    .iter()
+                    .any(|other| other.map_or(false, |other| other.package_name == name));
+                if !name.is_empty() && duplicate {
+                    debug!(
+                        "{} has the same package name as an app loaded earlier. \
+                         IPC discovery will only find the earlier app.",
+                        name
+                    );
+                }
+            });
             procs[i] = process;
         }
 
         apps_in_flash_ptr = apps_in_flash_ptr.offset(flash_offset as isize);
-        app_memory_ptr = app_memory_ptr.offset(memory_offset as isize);
+        match allocation {
+            MemoryAllocation::BottomUp => {
+                app_memory_ptr = app_memory_ptr.offset(memory_offset as isize);
+            }
+            // Memory was taken from the top of the pool, so the bottom of
+            // the remaining memory stays where it is.
+            MemoryAllocation::TopDown => {}
+        }
         app_memory_size -= memory_offset;
     }
 }
 
+/// How process RAM is allocated out of the board's application memory pool.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MemoryAllocation {
+    /// Processes are packed one after another starting from the bottom of
+    /// the pool. Each process region is only as aligned as the previous
+    /// process left it.
+    BottomUp,
+    /// Processes are placed starting from the top of the pool, and each
+    /// process region is aligned to the power of two its size rounds up to.
+    /// This matches the MPU's alignment requirements, so less memory is
+    /// wasted rounding regions to fit.
+    TopDown,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Error {
     NoSuchApp,
     OutOfMemory,
+    /// There is not enough free memory right now, but there would be if the
+    /// process shrank its heap. Retrying later may succeed.
+    HeapContended,
     AddressOutOfBounds,
+    /// There is no valid, enabled app at the given flash address, or its
+    /// header describes a process that cannot run.
+    InvalidHeader,
+    /// The app requires a newer kernel than this one.
+    IncompatibleKernelVersion,
+    /// Every runtime process slot of the kernel is already in use.
+    NoFreeProcessSlot,
 }
 
 impl From<Error> for ReturnCode {
     fn from(err: Error) -> ReturnCode {
         match err {
             Error::OutOfMemory => ReturnCode::ENOMEM,
+            Error::HeapContended => ReturnCode::EBUSY,
             Error::AddressOutOfBounds => ReturnCode::EINVAL,
             Error::NoSuchApp => ReturnCode::EINVAL,
+            Error::InvalidHeader => ReturnCode::EINVAL,
+            Error::IncompatibleKernelVersion => ReturnCode::ENOSUPPORT,
+            Error::NoFreeProcessSlot => ReturnCode::ENOMEM,
         }
     }
 }
@@ -106,12 +202,260 @@ crate enum State {
     Running,
     Yielded,
     Fault,
+    /// The process faulted before it made its first syscall, i.e. it never
+    /// got through its initialization. It is not restarted, since it would
+    /// most likely fault the same way again.
+    StartFailed,
+    /// The process ran a `Task::Terminate` and will not run again unless it
+    /// is restarted.
+    Terminated,
+}
+
+impl State {
+    /// A short name for the state, for tables of processes. Unlike the
+    /// `Debug` output it can be padded to a column width.
+    crate fn name(&self) -> &'static str {
+        match *self {
+            State::Running => "Running",
+            State::Yielded => "Yielded",
+            State::Fault => "Fault",
+            State::StartFailed => "StartFailed",
+            State::Terminated => "Terminated",
+        }
+    }
+}
+
+/// Why a process cannot currently be scheduled.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NotRunnableReason {
+    /// The process faulted and has not been restarted.
+    Faulted,
+    /// The process faulted before it made its first syscall, and is not
+    /// restarted.
+    StartFailed,
+    /// The process was terminated by a `Task::Terminate`.
+    Terminated,
+    /// The process called `yield` and has no callbacks waiting to run.
+    YieldedNoTasks,
+}
+
+/// Why a process stopped executing and control returned to the kernel.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+crate enum ContextSwitchReason {
+    /// The process called into the kernel with a syscall.
+    SyscallFired,
+    /// The process faulted.
+    Fault,
+    /// The process was preempted by the systick timer or a hardware interrupt
+    /// without calling into the kernel.
+    Interrupted,
+}
+
+/// Faults that can be simulated with `Process::inject_fault()`. Each one
+/// corresponds to a bit in the Configurable Fault Status Register.
+#[cfg(any(test, feature = "fault_injection"))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultKind {
+    InstructionAccessViolation,
+    DataAccessViolation,
+    PreciseBusError,
+    UndefinedInstruction,
+    UnalignedAccess,
+    DivideByZero,
+}
+
+#[cfg(any(test, feature = "fault_injection"))]
+impl FaultKind {
+    /// The CFSR value the hardware reports for this fault.
+    fn cfsr(&self) -> u32 {
+        match *self {
+            FaultKind::InstructionAccessViolation => 0x01,
+            FaultKind::DataAccessViolation => 0x02,
+            FaultKind::PreciseBusError => 0x02 << 8,
+            FaultKind::UndefinedInstruction => 0x01 << 16,
+            FaultKind::UnalignedAccess => 0x100 << 16,
+            FaultKind::DivideByZero => 0x200 << 16,
+        }
+    }
+}
+
+/// The Cortex-M fault status registers, as captured by a hard fault handler.
+/// Formatting it lists the faults the registers indicate, one per line. Both
+/// the process fault report and the arch crate's kernel hard fault panic use
+/// this, so that there is a single decoder.
+///
+/// ARMv6-M (Cortex-M0/M0+) has no CFSR, HFSR, MMFAR or BFAR, so nothing fills
+/// these in there. Kernels built with the `armv6m` feature leave them out of
+/// the process fault report.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FaultStatus {
+    /// Configurable Fault Status Register.
+    pub cfsr: u32,
+    /// Hard Fault Status Register.
+    pub hfsr: u32,
+    /// MemManage Fault Address Register.
+    pub mmfar: u32,
+    /// Bus Fault Address Register.
+    pub bfar: u32,
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const CFSR_FAULTS: [(u32, &str); 18] = [
+            (1 << 0, "Instruction Access Violation:"),
+            (1 << 1, "Data Access Violation:"),
+            (1 << 3, "Memory Management Unstacking Fault:"),
+            (1 << 4, "Memory Management Stacking Fault:"),
+            (1 << 5, "Memory Management Lazy FP Fault:"),
+            (1 << 8, "Instruction Bus Error:"),
+            (1 << 9, "Precise Data Bus Error:"),
+            (1 << 10, "Imprecise Data Bus Error:"),
+            (1 << 11, "Bus Unstacking Fault:"),
+            (1 << 12, "Bus Stacking Fault:"),
+            (1 << 13, "Bus Lazy FP Fault:"),
+            (1 << 16, "Undefined Instruction Usage Fault:"),
+            (1 << 17, "Invalid State Usage Fault:"),
+            (1 << 18, "Invalid PC Load Usage Fault:"),
+            (1 << 19, "No Coprocessor Usage Fault:"),
+            (1 << 20, "Stack Overflow Usage Fault:"),
+            (1 << 24, "Unaligned Access Usage Fault:"),
+            (1 << 25, "Divide By Zero:"),
+        ];
+        const HFSR_FAULTS: [(u32, &str); 2] = [
+            (1 << 1, "Bus Fault on Vector Table Read:"),
+            (1 << 30, "Forced Hard Fault:"),
+        ];
+        const MMFARVALID: u32 = 1 << 7;
+        const BFARVALID: u32 = 1 << 15;
+
+        for &(bit, name) in CFSR_FAULTS.iter() {
+            if self.cfsr & bit != 0 {
+                write!(f, "{:<36}true\r\n", name)?;
+            }
+        }
+        for &(bit, name) in HFSR_FAULTS.iter() {
+            if self.hfsr & bit != 0 {
+                write!(f, "{:<36}true\r\n", name)?;
+            }
+        }
+
+        if self.cfsr & MMFARVALID != 0 {
+            write!(
+                f,
+                "{:<36}{:#010X}\r\n",
+                "Faulting Memory Address:", self.mmfar
+            )?;
+        }
+        if self.cfsr & BFARVALID != 0 {
+            write!(f, "{:<36}{:#010X}\r\n", "Bus Fault Address:", self.bfar)?;
+        }
+
+        if self.cfsr == 0 && self.hfsr == 0 {
+            write!(f, "No faults detected.\r\n")
+        } else {
+            write!(
+                f,
+                "{:<36}{:#010X}\r\n",
+                "Fault Status Register (CFSR):", self.cfsr
+            )?;
+            write!(
+                f,
+                "{:<36}{:#010X}\r\n",
+                "Hard Fault Status Register (HFSR):", self.hfsr
+            )
+        }
+    }
+}
+
+/// How much of each of its resources a process is using, as percentages of
+/// what is available to it. A dimension is `None` if the kernel cannot tell,
+/// e.g. because the process never reported where its heap or stack start.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ResourcePressure {
+    /// Heap used, out of the space between the heap start and the grant
+    /// region.
+    pub heap: Option<u8>,
+    /// Deepest the stack has been, out of the space below the stack start.
+    pub stack: Option<u8>,
+    /// Grant region used, out of the space between the program break and the
+    /// end of process memory it could grow into.
+    pub grant: Option<u8>,
+    /// Callbacks queued, out of how many the queue can hold.
+    pub callback_queue: Option<u8>,
+}
+
+/// The boundaries of the regions of a process's RAM, from the lowest address
+/// to the highest, as drawn in the memory map of `statistics_str`. The
+/// stack and heap boundaries come from the pointers the process reports for
+/// debugging (see memop 10 and 11), and are `0` if it has not reported them.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryMap {
+    /// Start of the process's RAM.
+    pub sram_start: usize,
+    /// Lowest address the stack pointer has reached.
+    pub stack_bottom: usize,
+    /// Where the stack starts; it grows down from here. The process's data
+    /// lies between here and `heap_start`.
+    pub stack_top: usize,
+    /// Start of the heap.
+    pub heap_start: usize,
+    /// End of the heap, i.e. the program break.
+    pub app_break: usize,
+    /// Start of the grant region, i.e. the kernel memory break.
+    pub grant_start: usize,
+    /// End of the process's RAM.
+    pub sram_end: usize,
+}
+
+/// Receives notifications about processes faulting and being restarted, so
+/// that, for example, a supervisor capsule does not have to poll for them.
+/// Set with `Kernel::set_process_state_observer()`.
+pub trait ProcessObserver {
+    /// The process `id` faulted. This is called before the kernel restarts
+    /// the process or panics, depending on the `FaultResponse`.
+    fn process_faulted(&self, id: AppId);
+
+    /// The process was restarted, after a fault or by
+    /// `Kernel::reset_all_processes()`. `id` is its new `AppId`, as the one
+    /// it had before is no longer valid.
+    fn process_restarted(&self, id: AppId);
+}
+
+/// Non-volatile storage for how many times each app has been restarted after
+/// a fault. Unlike `Process::get_restart_count()`, this survives a reboot, so
+/// a device can report its apps' crash history after losing power. Boards
+/// implement it on top of whatever storage they have, e.g. a flash page, and
+/// set it with `Kernel::set_restart_counter_store()`. Without a store no
+/// counts are persisted, so flash is not worn by restarts.
+pub trait RestartCounterStore {
+    /// The app named `package_name` faulted and is being restarted, so add one
+    /// to its persisted restart count. This is called from the kernel's
+    /// fault handling, so implementations should only record the restart
+    /// and write it out later if writing takes long.
+    fn increment_restart_count(&self, package_name: &'static str);
+}
+
+/// Checks the signature in an app's TBF header as the app is loaded, so that
+/// capsules can trust its app id. Boards implement it with whatever signature
+/// scheme and keys they use, and set it with `Kernel::set_app_verifier()`.
+pub trait AppVerifier {
+    /// Whether `signature` is a valid signature over the bytes in `signed`,
+    /// taken in order. These are the app's whole TBF, header included, apart
+    /// from the header checksum and the signature itself. `short_app_id` is
+    /// part of the signed bytes, and is passed separately so that the
+    /// verifier can pick a key by it.
+    fn verify(&self, short_app_id: u32, signed: [&[u8]; 3], signature: &[u8]) -> bool;
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FaultResponse {
+    /// Panic the kernel, printing the state of the process.
     Panic,
+    /// Restart the process from its init function.
     Restart,
+    /// Leave the process faulted. It is not run again unless it is restarted
+    /// explicitly, e.g. with `Kernel::reset_all_processes()`.
+    Stop,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -120,23 +464,48 @@ pub enum IPCType {
     Client,
 }
 
+/// Work queued for a process, to run the next time it yields.
 #[derive(Copy, Clone)]
-crate enum Task {
+pub enum Task {
     FunctionCall(FunctionCall),
     IPC((AppId, IPCType)),
+    /// Terminate the process once the tasks queued before this one have run.
+    /// See `Process::schedule_terminate()`.
+    Terminate,
 }
 
+/// A call into a process's code: the function at `pc`, with `r0` to `r3` as
+/// its arguments.
 #[derive(Copy, Clone, Debug)]
-crate struct FunctionCall {
-    crate r0: usize,
-    crate r1: usize,
-    crate r2: usize,
-    crate r3: usize,
-    crate pc: usize,
+pub struct FunctionCall {
+    pub r0: usize,
+    pub r1: usize,
+    pub r2: usize,
+    pub r3: usize,
+    pub pc: usize,
+}
+
+impl FunctionCall {
+    /// A call to the function at `pc` with arguments `r0` to `r3`. Cortex-M
+    /// only runs Thumb code, so the Thumb bit of `pc` is set here, whether or
+    /// not the address passed in has it.
+    pub fn new(pc: usize, r0: usize, r1: usize, r2: usize, r3: usize) -> FunctionCall {
+        FunctionCall {
+            r0: r0,
+            r1: r1,
+            r2: r2,
+            r3: r3,
+            pc: pc | 1,
+        }
+    }
 }
 
+/// Registers the hardware does not stack on exception entry, saved and
+/// restored by the arch crate's `switch_to_user`. The layout is part of the
+/// interface with the arch crate's assembly.
 #[derive(Default)]
-struct StoredRegs {
+#[repr(C)]
+crate struct StoredRegs {
     r4: usize,
     r5: usize,
     r6: usize,
@@ -145,6 +514,24 @@ struct StoredRegs {
     r9: usize,
     r10: usize,
     r11: usize,
+    #[cfg(feature = "fpu")]
+    fp: StoredFpRegs,
+}
+
+/// Floating point state for chips with an FPU. The callee-saved registers
+/// s16-s31 are not part of the exception frame, so the arch crate saves them
+/// here. If the process had an active floating point context when it last
+/// entered the kernel, the hardware stacked an extended frame that also holds
+/// s0-s15 and FPSCR, which changes how the kernel must pop the frame.
+#[cfg(feature = "fpu")]
+#[derive(Default)]
+#[repr(C)]
+struct StoredFpRegs {
+    s16_s31: [u32; 16],
+    /// Nonzero if the last exception frame stacked by the process is an
+    /// extended frame. Set by the arch crate on exception entry, and cleared
+    /// by the kernel when it replaces the frame with a basic one.
+    extended_frame: Cell<usize>,
 }
 
 /// State for helping with debugging apps.
@@ -169,13 +556,98 @@ struct ProcessDebug {
     /// What was the most recent syscall.
     last_syscall: Option<Syscall>,
 
+    /// The kernel tick (see `Kernel::ticks()`) at which the most recent
+    /// syscall was made, or at which the process (re)started or was last
+    /// resumed to run a callback, if that was later.
+    last_syscall_time: u64,
+
     /// How many callbacks were dropped because the queue was insufficiently
-    /// long.
+    /// long. IPC notifications are counted separately.
     dropped_callback_count: usize,
 
+    /// How many IPC notifications to this process were dropped because its
+    /// queue was full, e.g. because a service cannot keep up with its
+    /// clients.
+    dropped_ipc_count: usize,
+
+    /// How many times the kernel failed to allocate grant memory for this
+    /// process because the grant region would have run into the app's heap.
+    grant_alloc_failures: usize,
+
     /// How many times this process has entered into a fault condition and the
     /// kernel has restarted it.
     restart_count: usize,
+
+    /// The process's PC, LR and SP at the time of its most recent fault. These
+    /// are kept across restarts so the crash site is not lost.
+    last_fault_pc: Option<usize>,
+    last_fault_lr: Option<usize>,
+    last_fault_sp: Option<usize>,
+}
+
+/// How many words an app can pass to the kernel with memop before it has to
+/// commit them.
+const STAGED_WORDS_MAX: usize = 4;
+
+/// The byte process memory is filled with when stack painting is enabled
+/// (see `Kernel::set_stack_painting()`).
+const STACK_PAINT: u8 = 0xCE;
+
+/// How much memory, in bytes, a process starts out with below its initial
+/// stack pointer and break, unless its TBF header specifies otherwise.
+const DEFAULT_INITIAL_STACK_SIZE: usize = 128;
+
+/// Number of buckets in a process's `UnshareTable`.
+const UNSHARE_BUCKETS: usize = 8;
+
+/// When a process last unshared a buffer, as its ALLOW count at the time.
+/// `(driver_num, allow_num)` pairs are hashed into a fixed number of buckets,
+/// so recording an unshare never allocates and cannot fail. Pairs that share
+/// a bucket invalidate each other's buffers, which is safe: a driver only
+/// ever loses access early, never keeps it after an unshare.
+crate struct UnshareTable {
+    counts: [Cell<usize>; UNSHARE_BUCKETS],
+}
+
+impl UnshareTable {
+    fn new() -> UnshareTable {
+        UnshareTable {
+            counts: Default::default(),
+        }
+    }
+
+    /// The bucket that unshares of the buffer allowed to `driver_num` as
+    /// `allow_num` are recorded in.
+    crate fn bucket(driver_num: usize, allow_num: usize) -> usize {
+        driver_num.wrapping_mul(31).wrapping_add(allow_num) % UNSHARE_BUCKETS
+    }
+
+    fn unshared(&self, bucket: usize, allow_count: usize) {
+        self.counts[bucket].set(allow_count);
+    }
+
+    /// Whether a buffer shared when the ALLOW count was `allow_count` has not
+    /// been unshared since.
+    fn is_shared(&self, bucket: usize, allow_count: usize) -> bool {
+        self.counts[bucket].get() < allow_count
+    }
+
+    fn reset(&self) {
+        for count in self.counts.iter() {
+            count.set(0);
+        }
+    }
+}
+
+/// A multi-word value an app passes to the kernel one word per memop call.
+/// Words accumulate in `staging` until the app commits them, at which point
+/// they become available to the kernel as a whole.
+#[derive(Default)]
+struct StagedWords {
+    staging: [usize; STAGED_WORDS_MAX],
+    staging_len: usize,
+    committed: [usize; STAGED_WORDS_MAX],
+    committed_len: Option<usize>,
 }
 
 pub struct Process<'a> {
@@ -243,8 +715,20 @@ pub struct Process<'a> {
     /// Whether the scheduler can schedule this app.
     state: Cell<State>,
 
+    /// Whether the process has made a syscall since it was last (re)started,
+    /// i.e. whether it got through its initialization.
+    has_run: Cell<bool>,
+
+    /// Whether the process is running the fault handler declared in its TBF
+    /// header, and will be restarted once the handler is done.
+    in_fault_handler: Cell<bool>,
+
+    /// Whether the process's memory was filled with `STACK_PAINT` when it was
+    /// last (re)started.
+    stack_painted: Cell<bool>,
+
     /// How to deal with Faults occurring in the process
-    fault_response: FaultResponse,
+    fault_response: Cell<FaultResponse>,
 
     /// MPU regions are saved as a pointer-size pair.
     ///
@@ -259,34 +743,88 @@ pub struct Process<'a> {
     /// 32-byte aligned.
     mpu_regions: [Cell<(*const u8, math::PowerOfTwo)>; 5],
 
+    /// Set when the memory layout the MPU configuration is computed from (the
+    /// app break, the kernel memory break, or the IPC regions) has changed
+    /// since `setup_mpu()` last ran for this process.
+    mpu_needs_update: Cell<bool>,
+
     /// Essentially a list of callbacks that want to call functions in the
     /// process.
     tasks: MapCell<RingBuffer<'a, Task>>,
 
+    /// A small separate queue for callbacks that must not be lost behind a
+    /// full `tasks` queue, such as error notifications. It is drained before
+    /// `tasks`.
+    priority_tasks: MapCell<RingBuffer<'a, Task>>,
+
     /// Name of the app. Public so that IPC can use it.
     pub package_name: &'static str,
 
+    /// The driver numbers the app may use, from its TBF header, or `None` if
+    /// it may use any driver.
+    driver_permissions: Option<&'static [u32]>,
+
+    /// The app's id from its TBF header, if it has one.
+    short_app_id: Option<u32>,
+
+    /// Whether the signature in the app's TBF header was checked by the
+    /// board's `AppVerifier` and is valid.
+    credentials_verified: bool,
+
     /// Values kept so that we can print useful debug messages when apps fault.
     debug: MapCell<ProcessDebug>,
+
+    /// Optional sink for this process's debugging output. If this is empty
+    /// the output goes to the writer shared by all processes.
+    debug_sink: TakeCell<'static, Write>,
+
+    /// Words the app is passing to the kernel through memop.
+    staged_words: MapCell<StagedWords>,
+
+    /// Distinguishes this process from earlier processes in the same slot,
+    /// and from itself before a restart. `AppId`s carry it so that stale ones
+    /// can be rejected.
+    generation: Cell<usize>,
+
+    /// The deadline the process has set for itself with memop, if any. Only
+    /// used by the earliest-deadline-first scheduling policy.
+    deadline: Cell<Option<u32>>,
+
+    /// Higher priority processes run first under the priority scheduling
+    /// policy. All processes start out with priority 0.
+    priority: Cell<u8>,
+
+    /// How many tasks are queued for the process, counted as they are
+    /// queued and dequeued rather than from the queues' indices, so that
+    /// `drop_work()` can give back their kernel work even if the queues
+    /// were corrupted.
+    queued_task_work: Cell<usize>,
+
+    /// Number of ALLOW calls the process has made. Every buffer it shares is
+    /// tagged with the count at the time, see `allow_valid()`.
+    allow_count: Cell<usize>,
+
+    /// When the process last unshared a buffer, see `allow_unshared()`.
+    unshares: UnshareTable,
 }
 
 impl Process<'a> {
     crate fn schedule(&self, callback: FunctionCall) -> bool {
         // If this app is in the `Fault` state then we shouldn't schedule
         // any work for it.
-        if self.current_state() == State::Fault {
+        if !self.is_active() {
             return false;
         }
 
-        self.kernel.increment_work();
-
         let ret = self
             .tasks
             .map_or(false, |tasks| tasks.enqueue(Task::FunctionCall(callback)));
 
         // Make a note that we lost this callback if the enqueue function
         // fails.
-        if ret == false {
+        if ret {
+            self.task_queued();
+        } else {
             self.debug.map(|debug| {
                 debug.dropped_callback_count += 1;
             });
@@ -295,28 +833,157 @@ impl Process<'a> {
         ret
     }
 
-    crate fn schedule_ipc(&self, from: AppId, cb_type: IPCType) {
-        self.kernel.increment_work();
+    /// Like `schedule()`, but queue the callback on the process's priority
+    /// queue, which is separate from the normal one and runs first. Only for
+    /// the few callbacks that must be delivered even when a process is flooded
+    /// with others, as the priority queue is short.
+    crate fn schedule_high_priority(&self, callback: FunctionCall) -> bool {
+        if !self.is_active() {
+            return false;
+        }
 
+        let ret = self
+            .priority_tasks
+            .map_or(false, |tasks| tasks.enqueue(Task::FunctionCall(callback)));
+
+        if ret {
+            self.task_queued();
+        } else {
+            self.debug.map(|debug| {
+                debug.dropped_callback_count += 1;
+            });
+        }
+
+        ret
+    }
+
+    /// Queue a `Task::Terminate` for this process, so that it runs the tasks
+    /// already queued for it, e.g. a shutdown callback, and is then
+    /// terminated. Returns `false` if the process is not active or its task
+    /// queue is full.
+    pub fn schedule_terminate(&self) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+
+        let ret = self
+            .tasks
+            .map_or(false, |tasks| tasks.enqueue(Task::Terminate));
+        if ret {
+            self.task_queued();
+        }
+        ret
+    }
+
+    /// Stop the process for good, dropping any tasks still queued for it.
+    /// This is how a `Task::Terminate` is carried out.
+    crate fn terminate(&self) {
+        self.drop_work();
+        self.state.set(State::Terminated);
+    }
+
+    crate fn schedule_ipc(&self, from: AppId, cb_type: IPCType) {
         let ret = self
             .tasks
             .map_or(false, |tasks| tasks.enqueue(Task::IPC((from, cb_type))));
 
-        // Make a note that we lost this callback if the enqueue function
+        // Make a note that we lost this notification if the enqueue function
         // fails.
-        if ret == false {
+        if ret {
+            self.task_queued();
+        } else {
             self.debug.map(|debug| {
-                debug.dropped_callback_count += 1;
+                debug.dropped_ipc_count += 1;
             });
         }
     }
 
+    /// Whether the process may use the driver `driver_num`. Apps whose TBF
+    /// header does not list the drivers they use may use any driver.
+    pub fn has_driver_permission(&self, driver_num: usize) -> bool {
+        self.driver_permissions.map_or(true, |permitted| {
+            permitted.iter().any(|&num| num as usize == driver_num)
+        })
+    }
+
+    /// The app id from the process's TBF header, if it has one. Unless
+    /// `credentials_verified()` is true, the process could have chosen any id.
+    pub fn short_app_id(&self) -> Option<u32> {
+        self.short_app_id
+    }
+
+    /// Whether the process's app id and binary are signed, and the board's
+    /// `AppVerifier` accepted the signature.
+    pub fn credentials_verified(&self) -> bool {
+        self.credentials_verified
+    }
+
     /// Retrieve the current state of this process (i.e. is it running,
     /// yielded, or in a fault state).
+    crate fn generation(&self) -> usize {
+        self.generation.get()
+    }
+
+    /// Record that the process is sharing a buffer through ALLOW, and return
+    /// the tag for the `AppSlice` that will refer to it.
+    crate fn allow_shared(&self) -> usize {
+        self.allow_count.increment();
+        self.allow_count.get()
+    }
+
+    /// Record that the process stopped sharing the buffer it allowed to
+    /// `driver_num` as `allow_num`, so that `AppSlice`s for it are no longer
+    /// valid. This never fails, so the unshare always reaches the driver.
+    crate fn allow_unshared(&self, driver_num: usize, allow_num: usize) {
+        self.allow_count.increment();
+        self.unshares.unshared(
+            UnshareTable::bucket(driver_num, allow_num),
+            self.allow_count.get(),
+        );
+    }
+
+    /// Whether the buffer shared into unshare bucket `bucket` when the ALLOW
+    /// count was `allow_count` is still shared, i.e. the process has not
+    /// unshared it since.
+    crate fn allow_valid(&self, bucket: usize, allow_count: usize) -> bool {
+        self.unshares.is_shared(bucket, allow_count)
+    }
+
     crate fn current_state(&self) -> State {
         self.state.get()
     }
 
+    /// Whether the process is alive, i.e. running or yielded, as opposed to
+    /// faulted. Work should only be queued for, and memory only handed out
+    /// to, active processes.
+    pub fn is_active(&self) -> bool {
+        match self.state.get() {
+            State::Running | State::Yielded => true,
+            State::Fault | State::StartFailed | State::Terminated => false,
+        }
+    }
+
+    /// Return why this process cannot run right now, or `None` if the
+    /// scheduler would run it (it is running, or has yielded with callbacks
+    /// pending).
+    pub fn not_runnable_reason(&self) -> Option<NotRunnableReason> {
+        match self.state.get() {
+            State::Running => None,
+            State::Yielded if self.tasks_len() > 0 => None,
+            State::Yielded => Some(NotRunnableReason::YieldedNoTasks),
+            State::Fault => Some(NotRunnableReason::Faulted),
+            State::StartFailed => Some(NotRunnableReason::StartFailed),
+            State::Terminated => Some(NotRunnableReason::Terminated),
+        }
+    }
+
+    /// Whether the process has made a syscall since it was last (re)started.
+    /// A process that faults before this is true is left in the
+    /// `StartFailed` state rather than restarted.
+    pub fn has_run(&self) -> bool {
+        self.has_run.get()
+    }
+
     /// Move this process from the running state to the yield state.
     crate fn yield_state(&self) {
         let current_state = self.state.get();
@@ -328,82 +995,402 @@ impl Process<'a> {
 
     crate unsafe fn fault_state(&self) {
         write_volatile(&mut APP_FAULT, 0);
-        self.state.set(State::Fault);
 
-        match self.fault_response {
+        let start_failed = !self.has_run.get();
+        let fault_response = if self.kernel.panic_on_fault() {
+            FaultResponse::Panic
+        } else {
+            self.fault_response.get()
+        };
+        // Whatever happens next, the process will not resume where it faulted,
+        // so give back the kernel work it accounts for while it is still
+        // `Running`. A restart or fault handler queues new work of its own.
+        let running = self.state.get() == State::Running;
+        let work_held = self.queued_task_work.get() + running as usize;
+        let work_before = self.kernel.work();
+        self.drop_work();
+        debug_assert_eq!(
+            self.kernel.work(),
+            work_before - work_held,
+            "Faulted process did not give back its kernel work"
+        );
+        if start_failed {
+            self.state.set(State::StartFailed);
+        } else {
+            self.state.set(State::Fault);
+        }
+
+        // A fault handler returning faults on purpose, see
+        // `run_fault_handler()`. That is how it reports it is done, not a new
+        // fault.
+        let (pc, lr, sp) = (self.pc(), self.lr(), self.sp());
+        let handler_returned = self.in_fault_handler.get() && pc == 0;
+
+        if !handler_returned {
+            // Record where the process was when it faulted, before a restart
+            // resets its stack.
+            self.debug.map(|debug| {
+                debug.last_fault_pc = Some(pc);
+                debug.last_fault_lr = Some(lr);
+                debug.last_fault_sp = Some(sp);
+            });
+
+            // Let an observer know while the process still has the `AppId`
+            // it faulted with.
+            self.kernel.notify_process_faulted(self);
+        }
+
+        match fault_response {
             FaultResponse::Panic => {
                 // process faulted. Panic and print status
                 panic!("Process {} had a fault", self.package_name);
             }
-            FaultResponse::Restart => {
-                // Remove the tasks that were scheduled for the app from the
-                // amount of work queue.
-                let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
-                for _ in 0..tasks_len {
-                    self.kernel.decrement_work();
+            FaultResponse::Restart if start_failed => {
+                debug!(
+                    "{} faulted before its first syscall, not restarting it",
+                    self.package_name
+                );
+            }
+            FaultResponse::Restart => match self.fault_handler() {
+                Some(handler) if !self.in_fault_handler.get() => {
+                    self.run_fault_handler(handler, pc, lr, sp);
                 }
+                _ => {
+                    // Mark that we restarted this process.
+                    self.debug.map(|debug| {
+                        debug.restart_count += 1;
+                    });
+                    self.kernel.record_persistent_restart(self);
 
-                // And remove those tasks
-                self.tasks.map(|tasks| {
-                    tasks.empty();
-                });
+                    self.restart();
+                }
+            },
+            FaultResponse::Stop => {}
+        }
+    }
 
-                // Update debug information
-                self.debug.map(|debug| {
-                    // Mark that we restarted this process.
-                    debug.restart_count += 1;
+    /// Address of the fault handler the process's TBF header declares, if it
+    /// declares one inside the process's flash.
+    fn fault_handler(&self) -> Option<usize> {
+        self.header.get_fault_handler_offset().and_then(|offset| {
+            if (offset as usize) & !0x1 < self.flash.len() {
+                Some(self.flash_start() as usize + offset as usize)
+            } else {
+                None
+            }
+        })
+    }
 
-                    // Reset some state for the process.
-                    debug.syscall_count = 0;
-                    debug.last_syscall = None;
-                    debug.dropped_callback_count = 0;
-                });
+    /// Give a faulted process the chance to save or report what it can before
+    /// it is restarted, by calling its fault handler with the fault status
+    /// register and where the process faulted: `handler(cfsr, pc, lr, sp)`.
+    ///
+    /// The fault may have left the stack unusable, so the handler runs on a
+    /// fresh stack. Its return address is 0, so when the handler returns the
+    /// process faults, and it is restarted as it would have been without a
+    /// handler. It is restarted the same way if the handler itself faults.
+    ///
+    /// `fault_state()` has already given back the process's kernel work.
+    unsafe fn run_fault_handler(&self, handler: usize, pc: usize, lr: usize, sp: usize) {
+        self.in_fault_handler.set(true);
+        self.current_stack_pointer.set(self.original_stack_pointer);
+        self.yield_pc.set(0);
+        self.state.set(State::Yielded);
+
+        self.tasks.map(|tasks| {
+            tasks.enqueue(Task::FunctionCall(FunctionCall::new(
+                handler,
+                SCB_REGISTERS[1] as usize,
+                pc,
+                lr,
+                sp,
+            )));
+        });
+        self.task_queued();
+    }
+
+    /// How the kernel responds to this process faulting.
+    pub fn fault_response(&self) -> FaultResponse {
+        self.fault_response.get()
+    }
+
+    /// Change how the kernel responds to this process faulting, e.g. to stop
+    /// restarting a process that keeps faulting. Takes effect at the next
+    /// fault.
+    pub fn set_fault_response(&self, fault_response: FaultResponse) {
+        self.fault_response.set(fault_response);
+    }
+
+    /// Give back the kernel work this process accounts for and drop its
+    /// queued tasks. The running unit is only given back while the process is
+    /// still `Running`, so this must be called before the process leaves that
+    /// state, as `fault_state()` does. Calling it again gives back nothing.
+    crate fn drop_work(&self) {
+        // A running process accounts for one unit of the kernel's work, which
+        // it would have given back when it yielded.
+        if self.state.get() == State::Running {
+            self.kernel.decrement_work();
+        }
+
+        // Remove the tasks that were scheduled for the app from the amount
+        // of work queue. They are counted separately from the queues, so
+        // this is right even if the queue indices are corrupt.
+        for _ in 0..self.queued_task_work.get() {
+            self.kernel.decrement_work();
+        }
+        self.queued_task_work.set(0);
+
+        // And remove those tasks
+        self.empty_task_queues();
+    }
+
+    /// Remove all queued tasks, without giving back the kernel work they
+    /// account for.
+    fn empty_task_queues(&self) {
+        self.tasks.map(|tasks| {
+            tasks.empty();
+        });
+        self.priority_tasks.map(|tasks| {
+            tasks.empty();
+        });
+    }
+
+    /// Whether the indices of either task queue are out of bounds. The queues
+    /// are kept in the process's grant region, which the process cannot
+    /// write, so this only happens if kernel code corrupted that memory.
+    crate fn task_queues_corrupt(&self) -> bool {
+        !self.tasks.map_or(true, |tasks| tasks.indices_valid())
+            || !self
+                .priority_tasks
+                .map_or(true, |tasks| tasks.indices_valid())
+    }
+
+    /// Start the process over from its init function, as if it had just
+    /// been loaded: its queued tasks, grant regions, memory pointers and
+    /// per-run debug counters are all reset, and it gets a new `AppId`. This
+    /// is what `FaultResponse::Restart` does to a faulted process, but it can
+    /// be applied to a process in any state.
+    crate unsafe fn restart(&self) {
+        self.drop_work();
+        self.has_run.set(false);
+        self.in_fault_handler.set(false);
+
+        // Reset the debug information for the new run.
+        self.debug.map(|debug| {
+            debug.syscall_count = 0;
+            debug.last_syscall = None;
+            debug.last_syscall_time = self.kernel.ticks();
+            debug.dropped_callback_count = 0;
+            debug.dropped_ipc_count = 0;
+            debug.grant_alloc_failures = 0;
+        });
+
+        // The restarted process gets a new identity, so that capsules
+        // holding on to `AppId`s from before the restart cannot reach it.
+        self.generation.set(self.kernel.next_process_generation());
+
+        // The restarted process sets a deadline again if it wants one.
+        self.deadline.set(None);
+
+        // Drop any value the process was in the middle of passing in.
+        self.staged_words.map(|staged| {
+            *staged = StagedWords::default();
+        });
+
+        // We are going to start this process over again, so need
+        // the init_fn location.
+        let app_flash_address = self.flash_start();
+        let init_fn = self.init_fn();
+        self.yield_pc.set(init_fn);
+        self.psr.set(0x01000000);
+        self.state.set(State::Yielded);
+
+        // Need to reset the grant region, and forget the buffers the process
+        // unshared. `AppSlice`s from before the restart are invalid anyway,
+        // as they refer to the old `AppId`.
+        self.grant_ptrs_reset();
+        self.unshares.reset();
+        self.kernel_memory_break
+            .set(self.original_kernel_memory_break);
+
+        // Reset other memory pointers.
+        self.app_break.set(self.original_app_break);
+        self.current_stack_pointer.set(self.original_stack_pointer);
+        self.mpu_needs_update.set(true);
+
+        // Measure the stack of the new run from scratch.
+        if self.stack_painted.get() {
+            self.paint_stack();
+        }
+
+        // And queue up this app to be restarted.
+        let flash_protected_size = self.header.get_protected_size() as usize;
+        let flash_app_start = app_flash_address as usize + flash_protected_size;
+
+        self.tasks.map(|tasks| {
+            tasks.enqueue(Task::FunctionCall(FunctionCall::new(
+                init_fn,
+                flash_app_start,
+                self.memory.as_ptr() as usize,
+                self.memory.len() as usize,
+                self.app_break.get() as usize,
+            )));
+        });
+
+        self.task_queued();
+
+        self.kernel.notify_process_restarted(self);
+    }
 
-                // We are going to start this process over again, so need
-                // the init_fn location.
-                let app_flash_address = self.flash_start();
-                let init_fn = app_flash_address
-                    .offset(self.header.get_init_function_offset() as isize)
-                    as usize;
-                self.yield_pc.set(init_fn);
-                self.psr.set(0x01000000);
-                self.state.set(State::Yielded);
-
-                // Need to reset the grant region.
-                self.grant_ptrs_reset();
-                self.kernel_memory_break
-                    .set(self.original_kernel_memory_break);
-
-                // Reset other memory pointers.
-                self.app_break.set(self.original_app_break);
-                self.current_stack_pointer.set(self.original_stack_pointer);
-
-                // And queue up this app to be restarted.
-                let flash_protected_size = self.header.get_protected_size() as usize;
-                let flash_app_start = app_flash_address as usize + flash_protected_size;
-
-                self.tasks.map(|tasks| {
-                    tasks.enqueue(Task::FunctionCall(FunctionCall {
-                        pc: init_fn,
-                        r0: flash_app_start,
-                        r1: self.memory.as_ptr() as usize,
-                        r2: self.memory.len() as usize,
-                        r3: self.app_break.get() as usize,
-                    }));
+    /// Return where each region of the process's RAM starts and ends, e.g. so
+    /// a capsule can report the process's memory usage.
+    pub fn memory_regions(&self) -> MemoryMap {
+        let (heap_start, stack_top, stack_bottom) =
+            self.debug
+                .map_or((ptr::null(), ptr::null(), ptr::null()), |debug| {
+                    (
+                        debug.app_heap_start_pointer.unwrap_or(ptr::null()),
+                        debug.app_stack_start_pointer.unwrap_or(ptr::null()),
+                        debug.min_stack_pointer,
+                    )
                 });
+        MemoryMap {
+            sram_start: self.mem_start() as usize,
+            stack_bottom: stack_bottom as usize,
+            stack_top: stack_top as usize,
+            heap_start: heap_start as usize,
+            app_break: self.app_break.get() as usize,
+            grant_start: self.kernel_memory_break.get() as usize,
+            sram_end: self.mem_end() as usize,
+        }
+    }
 
-                self.kernel.increment_work();
+    /// Return how close this process is to running out of each of its
+    /// resources. Computed from the process's memory boundaries and the
+    /// pointers it reported for debugging.
+    pub fn resource_pressure(&self) -> ResourcePressure {
+        fn percent(used: usize, available: usize) -> Option<u8> {
+            if available == 0 {
+                Some(100)
+            } else {
+                Some((used.min(available) * 100 / available) as u8)
             }
         }
+
+        let mem_start = self.mem_start() as usize;
+        let mem_end = self.mem_end() as usize;
+        let app_break = self.app_break.get() as usize;
+        let kernel_memory_break = self.kernel_memory_break.get() as usize;
+
+        self.debug.map_or(ResourcePressure::default(), |debug| {
+            let heap = debug.app_heap_start_pointer.and_then(|heap_start| {
+                let heap_start = heap_start as usize;
+                percent(
+                    app_break.saturating_sub(heap_start),
+                    kernel_memory_break.saturating_sub(heap_start),
+                )
+            });
+            let stack = debug.app_stack_start_pointer.and_then(|stack_start| {
+                let stack_start = stack_start as usize;
+                percent(
+                    stack_start.saturating_sub(debug.min_stack_pointer as usize),
+                    stack_start.saturating_sub(mem_start),
+                )
+            });
+            ResourcePressure {
+                heap: heap,
+                stack: stack,
+                grant: percent(mem_end - kernel_memory_break, mem_end - app_break),
+                callback_queue: self
+                    .tasks
+                    .map(|tasks| percent(tasks.len(), tasks.capacity()))
+                    .and_then(|pressure| pressure),
+            }
+        })
+    }
+
+    /// Ask the scheduler to run the process with IPC id `peer` next. IPC ids
+    /// are the ones IPC service discovery hands out, i.e. the process's slot
+    /// plus one. Returns `false` if there is no such process.
+    crate fn hint_next_process(&self, peer: usize) -> bool {
+        peer.checked_sub(1)
+            .map_or(false, |index| self.kernel.set_next_process_hint(index))
+    }
+
+    /// The deadline the process has set for itself, if any. See
+    /// `SchedulingPolicy::EarliestDeadlineFirst`.
+    pub fn deadline(&self) -> Option<u32> {
+        self.deadline.get()
+    }
+
+    crate fn set_deadline(&self, deadline: Option<u32>) {
+        self.deadline.set(deadline);
+    }
+
+    /// The process's priority. See `SchedulingPolicy::Priority`.
+    pub fn priority(&self) -> u8 {
+        self.priority.get()
+    }
+
+    /// Change the process's priority, e.g. so a board can boost a latency
+    /// sensitive app at runtime. Processes can also set their own priority
+    /// with memop. The priority is kept if the process is restarted.
+    pub fn set_priority(&self, priority: u8) {
+        self.priority.set(priority);
+    }
+
+    /// Make the kernel handle this process as if it had just hit a hardware
+    /// fault of the given kind. The fault status registers are filled in as
+    /// the fault handler would, so the process is restarted or the kernel
+    /// panics (and reports the fault) according to its `FaultResponse`.
+    ///
+    /// This is only meant for exercising fault handling when testing and
+    /// debugging, as the process did nothing wrong, and so is only available
+    /// with the kernel's `fault_injection` feature.
+    #[cfg(any(test, feature = "fault_injection"))]
+    pub unsafe fn inject_fault(&self, kind: FaultKind) {
+        SCB_REGISTERS = [0, kind.cfsr(), 0, 0, 0];
+        self.fault_state();
+    }
+
+    /// Return how many tasks are currently queued for this process.
+    crate fn tasks_len(&self) -> usize {
+        self.tasks.map_or(0, |tasks| tasks.len())
+            + self.priority_tasks.map_or(0, |tasks| tasks.len())
+    }
+
+    /// How many tasks are queued for the process, in both its priority and
+    /// its normal queue.
+    pub fn queued_task_count(&self) -> usize {
+        self.tasks_len()
+    }
+
+    /// Call `f` on each task queued for the process, in the order they will
+    /// run, without removing them. Useful to see what a process that is
+    /// backed up is waiting on.
+    pub fn peek_tasks<F: FnMut(&Task)>(&self, mut f: F) {
+        self.priority_tasks
+            .map(|tasks| tasks.iter().for_each(&mut f));
+        self.tasks.map(|tasks| tasks.iter().for_each(&mut f));
     }
 
     crate fn dequeue_task(&self) -> Option<Task> {
-        self.tasks.map_or(None, |tasks| {
-            tasks.dequeue().map(|cb| {
+        self.priority_tasks
+            .map_or(None, |tasks| tasks.dequeue())
+            .or_else(|| self.tasks.map_or(None, |tasks| tasks.dequeue()))
+            .map(|cb| {
+                self.queued_task_work.decrement();
                 self.kernel.decrement_work();
                 cb
             })
-        })
+    }
+
+    /// Account for a task that was just queued for the process as one unit
+    /// of the kernel's work, which `dequeue_task()` or `drop_work()` gives
+    /// back.
+    fn task_queued(&self) {
+        self.queued_task_work.increment();
+        self.kernel.increment_work();
     }
 
     crate fn mem_start(&self) -> *const u8 {
@@ -414,10 +1401,6 @@ impl Process<'a> {
         unsafe { self.memory.as_ptr().offset(self.memory.len() as isize) }
     }
 
-    fn mem_break(&self) -> *const u8 {
-        self.kernel_memory_break.get()
-    }
-
     crate fn flash_start(&self) -> *const u8 {
         self.flash.as_ptr()
     }
@@ -426,6 +1409,14 @@ impl Process<'a> {
         ((self.flash.as_ptr() as usize) + self.header.get_protected_size() as usize) as *const u8
     }
 
+    /// Address of the process's entry point. TBF headers declare a single
+    /// entry point (`init_offset` in the v2 Main TLV, `entry_offset` in v1);
+    /// there is no separate `main` entry. This is where the process starts
+    /// both when it is first created and when it is restarted after a fault.
+    crate fn init_fn(&self) -> usize {
+        self.flash_start() as usize + self.header.get_init_function_offset() as usize
+    }
+
     crate fn flash_end(&self) -> *const u8 {
         unsafe { self.flash.as_ptr().offset(self.flash.len() as isize) }
     }
@@ -434,6 +1425,22 @@ impl Process<'a> {
         self.kernel_memory_break.get()
     }
 
+    /// How many bytes at the end of the process's memory the kernel is using:
+    /// the process's own kernel state (this struct, its callback queues and
+    /// grant pointers) plus the grant regions capsules have allocated. Grant
+    /// memory is never freed while the process runs, so this is also the most
+    /// the kernel has used, which helps pick a `min_app_ram_size` for the app.
+    pub fn grant_bytes_used(&self) -> usize {
+        self.mem_end() as usize - self.kernel_memory_break.get() as usize
+    }
+
+    /// How many bytes are left between the app's break and the kernel's
+    /// memory, i.e. how much more either the app's heap or the grant regions
+    /// can grow.
+    pub fn grant_bytes_free(&self) -> usize {
+        (self.kernel_memory_break.get() as usize).saturating_sub(self.app_break.get() as usize)
+    }
+
     crate fn number_writeable_flash_regions(&self) -> usize {
         self.header.number_writeable_flash_regions()
     }
@@ -442,7 +1449,94 @@ impl Process<'a> {
         self.header.get_writeable_flash_region(region_index)
     }
 
-    crate fn update_stack_start_pointer(&self, stack_pointer: *const u8) {
+    /// Fill `out` with the `(offset, size)` of each writeable flash region
+    /// declared in the process's header, up to `out.len()` of them. Returns
+    /// the total number of regions, which is larger than `out.len()` if not
+    /// all of them fit.
+    pub fn writeable_flash_regions(&self, out: &mut [(u32, u32)]) -> usize {
+        let count = self.number_writeable_flash_regions();
+        for (i, region) in out.iter_mut().take(count).enumerate() {
+            *region = self.get_writeable_flash_region(i);
+        }
+        count
+    }
+
+    /// Check whether `[addr, addr + len)` lies entirely within one of the
+    /// writeable flash regions declared in the process's header, e.g. before
+    /// letting a capsule write to flash on the process's behalf. Only the part
+    /// of each region inside the process's own flash, after its protected
+    /// region, counts, whatever the header says.
+    pub fn in_writeable_flash_region(&self, addr: usize, len: usize) -> bool {
+        let flash_start = self.flash_start() as usize;
+        let flash_end = self.flash_end() as usize;
+        let end = match addr.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        let unprotected_start =
+            match flash_start.checked_add(self.header.get_protected_size() as usize) {
+                Some(start) => start,
+                None => return false,
+            };
+        (0..self.number_writeable_flash_regions()).any(|i| {
+            let (offset, size) = self.get_writeable_flash_region(i);
+            let region_start = flash_start.checked_add(offset as usize);
+            let region_end = region_start.and_then(|start| start.checked_add(size as usize));
+            match (region_start, region_end) {
+                (Some(region_start), Some(region_end)) => {
+                    let region_start = region_start.max(unprotected_start);
+                    let region_end = region_end.min(flash_end);
+                    size > 0 && addr >= region_start && end <= region_end
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Get the value of the TLV block of type `tlv_type` in the process's TBF
+    /// header, for example so a capsule can look up metadata the kernel does
+    /// not interpret itself. Returns `None` if the header has no such block.
+    pub fn tbf_tlv(&self, tlv_type: u16) -> Option<&'static [u8]> {
+        self.header.get_tlv(tlv_type)
+    }
+
+    /// Fill the process's memory below its initial kernel memory break with
+    /// `STACK_PAINT`, so that `stack_high_water()` can later tell how far the
+    /// stack has grown. Only valid before the process (re)starts, as it
+    /// overwrites everything the process has in memory.
+    unsafe fn paint_stack(&self) {
+        let start = self.mem_start();
+        let len = self.original_kernel_memory_break as usize - start as usize;
+        ptr::write_bytes(start as *mut u8, STACK_PAINT, len);
+        self.stack_painted.set(true);
+    }
+
+    /// The most stack, in bytes, the process has used since it was last
+    /// (re)started. Unlike the stack pointer recorded at each syscall, this
+    /// also counts stack used between syscalls, as it looks for the lowest
+    /// address below the stack start that no longer holds the paint written
+    /// by stack painting. Returns `None` if the process's memory was not
+    /// painted (see `Kernel::set_stack_painting()`) or it has not reported
+    /// where its stack starts (memop 10).
+    pub fn stack_high_water(&self) -> Option<usize> {
+        let stack_start = self
+            .debug
+            .map_or(None, |debug| debug.app_stack_start_pointer);
+        match stack_start {
+            Some(stack_start) if self.stack_painted.get() => {
+                // The stack grows down from its start towards the start of
+                // process memory.
+                let stack_start = stack_start as usize;
+                let lowest_used = (self.mem_start() as usize..stack_start)
+                    .find(|&addr| unsafe { read_volatile(addr as *const u8) } != STACK_PAINT)
+                    .unwrap_or(stack_start);
+                Some(stack_start - lowest_used)
+            }
+            _ => None,
+        }
+    }
+
+    crate fn update_stack_start_pointer(&self, stack_pointer: *const u8) -> Result<(), Error> {
         if stack_pointer >= self.mem_start() && stack_pointer < self.mem_end() {
             self.debug.map(|debug| {
                 debug.app_stack_start_pointer = Some(stack_pointer);
@@ -451,14 +1545,20 @@ impl Process<'a> {
                 // we had could be entirely wrong by now.
                 debug.min_stack_pointer = stack_pointer;
             });
+            Ok(())
+        } else {
+            Err(Error::AddressOutOfBounds)
         }
     }
 
-    crate fn update_heap_start_pointer(&self, heap_pointer: *const u8) {
+    crate fn update_heap_start_pointer(&self, heap_pointer: *const u8) -> Result<(), Error> {
         if heap_pointer >= self.mem_start() && heap_pointer < self.mem_end() {
             self.debug.map(|debug| {
                 debug.app_heap_start_pointer = Some(heap_pointer);
             });
+            Ok(())
+        } else {
+            Err(Error::AddressOutOfBounds)
         }
     }
 
@@ -498,12 +1598,35 @@ impl Process<'a> {
             Some(region) => mpu.set_mpu(region),
         }
 
-        // Disallow access to grant region
-        let grant_len = unsafe {
-            math::PowerOfTwo::ceiling(
-                self.memory.as_ptr().offset(self.memory.len() as isize) as u32
-                    - (self.kernel_memory_break.get() as u32),
-            ).as_num::<u32>()
+        // Disallow access to grant region. The MPU can only cover it with a
+        // power-of-two region aligned to its size, or with whole subregions
+        // of the region covering the process's RAM. The latter is needed when
+        // the RAM was sized in subregions and the grant region has grown
+        // past one subregion, as its power-of-two size may then reach below
+        // the start of the process's RAM.
+        let mem_end = data_start + data_len;
+        let kernel_memory_break = self.kernel_memory_break.get() as usize;
+        // The grant region is what lies between the kernel memory break and
+        // the end of the process's memory. A break outside the process's
+        // memory is a kernel bug, and would otherwise surface as an
+        // underflowed grant size and an unhelpful "Infeasible MPU allocation".
+        assert!(
+            kernel_memory_break >= data_start && kernel_memory_break <= mem_end,
+            "{}: kernel memory break {:#x} outside of process memory [{:#x}, {:#x})",
+            self.package_name,
+            kernel_memory_break,
+            data_start,
+            mem_end
+        );
+        let grant_size = (mem_end - kernel_memory_break) as u32;
+        let grant_len = {
+            let pow2_len = math::PowerOfTwo::ceiling(grant_size).as_num::<u32>();
+            let subregion_size = math::closest_power_of_two(self.memory.len() as u32) / 8;
+            if self.memory.len().is_power_of_two() || pow2_len <= subregion_size {
+                pow2_len
+            } else {
+                (grant_size + subregion_size - 1) / subregion_size * subregion_size
+            }
         };
         let grant_base = unsafe {
             self.memory
@@ -549,6 +1672,16 @@ impl Process<'a> {
                 Some(region) => mpu.set_mpu(region),
             }
         }
+
+        self.mpu_needs_update.set(false);
+    }
+
+    /// Whether the MPU configuration for this process is out of date, i.e.
+    /// whether the process's break, grant region, or IPC regions have changed
+    /// since `setup_mpu()` last ran. The scheduler uses this to decide whether
+    /// the MPU must be reconfigured before resuming a process it just ran.
+    pub fn mpu_needs_update(&self) -> bool {
+        self.mpu_needs_update.get()
     }
 
     crate fn add_mpu_region(&self, base: *const u8, size: u32) -> bool {
@@ -557,10 +1690,12 @@ impl Process<'a> {
             for region in self.mpu_regions.iter() {
                 if region.get().0 == ptr::null() {
                     region.set((base, mpu_size));
+                    self.mpu_needs_update.set(true);
                     return true;
                 } else if region.get().0 == base {
                     if region.get().1 < mpu_size {
                         region.set((base, mpu_size));
+                        self.mpu_needs_update.set(true);
                     }
                     return true;
                 }
@@ -569,31 +1704,72 @@ impl Process<'a> {
         return false;
     }
 
+    /// Create a process from the app at `app_flash_address`, with its RAM
+    /// taken from `remaining_app_memory`. Also returns how much flash to skip
+    /// to get to the next app and how much of the memory was used, which are
+    /// set even if no process was created, e.g. for padding.
     crate unsafe fn create(
         kernel: &'static Kernel,
         app_flash_address: *const u8,
         remaining_app_memory: *mut u8,
         remaining_app_memory_size: usize,
         fault_response: FaultResponse,
-    ) -> (Option<&'static Process<'a>>, usize, usize) {
+        allocation: MemoryAllocation,
+    ) -> (Result<&'static Process<'a>, Error>, usize, usize) {
         if let Some(tbf_header) = tbfheader::parse_and_validate_tbf_header(app_flash_address) {
             let app_flash_size = tbf_header.get_total_size() as usize;
 
             // If this isn't an app (i.e. it is padding) or it is an app but it
             // isn't enabled, then we can skip it but increment past its flash.
             if !tbf_header.is_app() || !tbf_header.enabled() {
-                return (None, app_flash_size, 0);
+                return (Err(Error::InvalidHeader), app_flash_size, 0);
             }
 
             // Otherwise, actually load the app.
             let mut min_app_ram_size = tbf_header.get_minimum_app_ram_size();
             let package_name = tbf_header.get_package_name(app_flash_address);
-            let init_fn =
-                app_flash_address.offset(tbf_header.get_init_function_offset() as isize) as usize;
 
-            // Set the initial process stack and memory to 128 bytes.
-            let initial_stack_pointer = remaining_app_memory.offset(128);
-            let initial_sbrk_pointer = remaining_app_memory.offset(128);
+            // Skip apps built against a newer kernel, since they may rely on
+            // system calls this kernel does not provide. Apps that do not
+            // declare a kernel version are loaded.
+            if let Some(required_version) = tbf_header.get_kernel_version() {
+                if required_version > ::KERNEL_VERSION {
+                    debug!(
+                        "{:?} requires kernel version {}.{}, but this is {}.{}",
+                        package_name,
+                        required_version.0,
+                        required_version.1,
+                        ::KERNEL_VERSION.0,
+                        ::KERNEL_VERSION.1
+                    );
+                    return (Err(Error::IncompatibleKernelVersion), app_flash_size, 0);
+                }
+            }
+
+            // The init function must be inside the app's own flash, or a
+            // malformed header could start the process in another app or in
+            // the kernel. The Thumb bit is not part of the offset.
+            let init_function_offset = tbf_header.get_init_function_offset() as usize;
+            if init_function_offset & !0x1 >= app_flash_size {
+                debug!(
+                    "{:?} process image invalid. \
+                     init_fn offset {:#X} is outside the app's {:#X} bytes of flash",
+                    package_name, init_function_offset, app_flash_size
+                );
+                return (Err(Error::InvalidHeader), app_flash_size, 0);
+            }
+
+            // This must match `init_fn()`, which the restart path uses.
+            let init_fn = app_flash_address.offset(init_function_offset as isize) as usize;
+
+            if (init_fn & 0x1) != 1 {
+                debug!(
+                    "{:?} process image invalid. \
+                     init_fn address must end in 1 to be Thumb, got {:#X}",
+                    package_name, init_fn
+                );
+                return (Err(Error::InvalidHeader), app_flash_size, 0);
+            }
 
             // First determine how much space we need in the application's
             // memory space just for kernel and grant state. We need to make
@@ -607,34 +1783,99 @@ impl Process<'a> {
             // Allocate memory for callback ring buffer.
             let callback_size = mem::size_of::<Task>();
             let callback_len = 10;
-            let callbacks_offset = callback_len * callback_size;
+            // The priority queue holds two callbacks, as a ring buffer keeps
+            // one slot empty.
+            let priority_callback_len = 3;
+            let callbacks_offset = (callback_len + priority_callback_len) * callback_size;
 
             // Make room to store this process's metadata.
             let process_struct_offset = mem::size_of::<Process>();
+            let kernel_state_size = grant_ptrs_offset + callbacks_offset + process_struct_offset;
 
             // Need to make sure that the amount of memory we allocate for
             // this process at least covers this state.
-            if min_app_ram_size
-                < (grant_ptrs_offset + callbacks_offset + process_struct_offset) as u32
-            {
-                min_app_ram_size =
-                    (grant_ptrs_offset + callbacks_offset + process_struct_offset) as u32;
+            if min_app_ram_size < kernel_state_size as u32 {
+                min_app_ram_size = kernel_state_size as u32;
             }
 
-            // TODO round app_ram_size up to a closer MPU unit.
-            // This is a very conservative approach that rounds up to power of
-            // two. We should be able to make this closer to what we actually need.
-            let app_ram_size = math::closest_power_of_two(min_app_ram_size) as usize;
-
-            // Check that we can actually give this app this much memory.
-            if app_ram_size > remaining_app_memory_size {
-                panic!(
-                    "{:?} failed to load. Insufficient memory. Requested {} have {}",
-                    package_name, app_ram_size, remaining_app_memory_size
+            // The MPU protects the app's RAM with a single power-of-two sized
+            // region. If that region is aligned to its size, the MPU can also
+            // disable some of its eight subregions, so the app only needs
+            // `min_app_ram_size` rounded up to a whole number of subregions.
+            // For example, an app needing 5 KB uses 5 KB rather than 8 KB.
+            let mpu_region_size = math::closest_power_of_two(min_app_ram_size) as usize;
+            let subregion_rounded_size = {
+                let subregion_size = mpu_region_size / 8;
+                let min_size = min_app_ram_size as usize;
+                if subregion_size >= 32 {
+                    (min_size + subregion_size - 1) / subregion_size * subregion_size
+                } else {
+                    mpu_region_size
+                }
+            };
+
+            // Pick where in the remaining memory this app goes, how much RAM
+            // it gets, and how much of the remaining memory that uses up.
+            let placement = match allocation {
+                MemoryAllocation::BottomUp => {
+                    // Only use the tighter size if the region is aligned,
+                    // otherwise the MPU may not be able to cover it.
+                    let app_ram_size = if remaining_app_memory as usize % mpu_region_size == 0 {
+                        subregion_rounded_size
+                    } else {
+                        mpu_region_size
+                    };
+                    if app_ram_size <= remaining_app_memory_size {
+                        Some((remaining_app_memory, app_ram_size, app_ram_size))
+                    } else {
+                        None
+                    }
+                }
+                MemoryAllocation::TopDown => {
+                    let bottom = remaining_app_memory as usize;
+                    let top = bottom + remaining_app_memory_size;
+                    top.checked_sub(subregion_rounded_size)
+                        .map(|start| start & !(mpu_region_size - 1))
+                        .filter(|&start| start >= bottom)
+                        .map(|start| (start as *mut u8, subregion_rounded_size, top - start))
+                }
+            };
+
+            // Check that we can actually give this app this much memory. If
+            // not, skip this app but keep going so that one misconfigured app
+            // does not prevent the rest from loading.
+            let (app_memory_start, app_ram_size, memory_used) = match placement {
+                Some(placement) => placement,
+                None => {
+                    debug!(
+                        "{:?} failed to load. Insufficient memory. Requested {} have {}",
+                        package_name, subregion_rounded_size, remaining_app_memory_size
+                    );
+                    return (Err(Error::OutOfMemory), app_flash_size, 0);
+                }
+            };
+
+            // The app starts out with its stack pointer and break this far
+            // into its memory. It must leave room for the kernel's state at
+            // the top, and keep the stack pointer 8 byte aligned.
+            let initial_stack_size = tbf_header
+                .get_initial_stack_size()
+                .map_or(DEFAULT_INITIAL_STACK_SIZE, |size| size as usize);
+            if initial_stack_size % 8 != 0 || initial_stack_size > app_ram_size - kernel_state_size
+            {
+                debug!(
+                    "{:?} failed to load. Initial stack size {} is unaligned or does not fit in \
+                     its {} bytes of RAM",
+                    package_name, initial_stack_size, app_ram_size
                 );
+                return (Err(Error::InvalidHeader), app_flash_size, 0);
             }
 
-            let app_memory = slice::from_raw_parts_mut(remaining_app_memory, app_ram_size);
+            let app_memory = slice::from_raw_parts_mut(app_memory_start, app_ram_size);
+
+            // Set the initial process stack and memory.
+            let initial_stack_pointer = app_memory_start.offset(initial_stack_size as isize);
+            let initial_sbrk_pointer = app_memory_start.offset(initial_stack_size as isize);
 
             // Set up initial grant region.
             let mut kernel_memory_break = app_memory.as_mut_ptr().offset(app_memory.len() as isize);
@@ -655,9 +1896,13 @@ impl Process<'a> {
             kernel_memory_break = kernel_memory_break.offset(-(callbacks_offset as isize));
 
             // Set up ring buffer.
-            let callback_buf =
-                slice::from_raw_parts_mut(kernel_memory_break as *mut Task, callback_len);
+            let callback_buf = slice::from_raw_parts_mut(
+                kernel_memory_break as *mut Task,
+                callback_len + priority_callback_len,
+            );
+            let (callback_buf, priority_callback_buf) = callback_buf.split_at_mut(callback_len);
             let tasks = RingBuffer::new(callback_buf);
+            let priority_tasks = RingBuffer::new(priority_callback_buf);
 
             // Last thing is the process struct.
             kernel_memory_break = kernel_memory_break.offset(-(process_struct_offset as isize));
@@ -691,7 +1936,9 @@ impl Process<'a> {
             process.psr = Cell::new(0x01000000);
 
             process.state = Cell::new(State::Yielded);
-            process.fault_response = fault_response;
+            process.has_run = Cell::new(false);
+            process.in_fault_handler = Cell::new(false);
+            process.fault_response = Cell::new(fault_response);
 
             process.mpu_regions = [
                 Cell::new((ptr::null(), math::PowerOfTwo::zero())),
@@ -700,8 +1947,19 @@ impl Process<'a> {
                 Cell::new((ptr::null(), math::PowerOfTwo::zero())),
                 Cell::new((ptr::null(), math::PowerOfTwo::zero())),
             ];
+            process.mpu_needs_update = Cell::new(true);
             process.tasks = MapCell::new(tasks);
+            process.priority_tasks = MapCell::new(priority_tasks);
             process.package_name = package_name;
+            process.driver_permissions = process.header.get_driver_permissions();
+            process.short_app_id = process.header.get_short_app_id();
+            let signature = process.header.get_signature(process.flash);
+            process.credentials_verified = match (process.short_app_id, signature) {
+                (Some(short_app_id), Some((signed, signature))) => {
+                    kernel.verify_app(short_app_id, signed, signature)
+                }
+                _ => false,
+            };
 
             process.debug = MapCell::new(ProcessDebug {
                 app_heap_start_pointer: app_heap_start_pointer,
@@ -709,36 +1967,47 @@ impl Process<'a> {
                 min_stack_pointer: initial_stack_pointer,
                 syscall_count: 0,
                 last_syscall: None,
+                last_syscall_time: kernel.ticks(),
                 dropped_callback_count: 0,
+                dropped_ipc_count: 0,
+                grant_alloc_failures: 0,
                 restart_count: 0,
+                last_fault_pc: None,
+                last_fault_lr: None,
+                last_fault_sp: None,
             });
-
-            if (init_fn & 0x1) != 1 {
-                panic!(
-                    "{:?} process image invalid. \
-                     init_fn address must end in 1 to be Thumb, got {:#X}",
-                    package_name, init_fn
-                );
+            process.debug_sink = TakeCell::empty();
+            process.staged_words = MapCell::new(StagedWords::default());
+            process.generation = Cell::new(kernel.next_process_generation());
+            process.deadline = Cell::new(None);
+            process.priority = Cell::new(0);
+            process.queued_task_work = Cell::new(0);
+            process.allow_count = Cell::new(0);
+            process.unshares = UnshareTable::new();
+
+            process.stack_painted = Cell::new(false);
+            if kernel.stack_painting() {
+                process.paint_stack();
             }
 
             let flash_protected_size = process.header.get_protected_size() as usize;
             let flash_app_start = app_flash_address as usize + flash_protected_size;
 
             process.tasks.map(|tasks| {
-                tasks.enqueue(Task::FunctionCall(FunctionCall {
-                    pc: init_fn,
-                    r0: flash_app_start,
-                    r1: process.memory.as_ptr() as usize,
-                    r2: process.memory.len() as usize,
-                    r3: process.app_break.get() as usize,
-                }));
+                tasks.enqueue(Task::FunctionCall(FunctionCall::new(
+                    init_fn,
+                    flash_app_start,
+                    process.memory.as_ptr() as usize,
+                    process.memory.len() as usize,
+                    process.app_break.get() as usize,
+                )));
             });
 
-            kernel.increment_work();
+            process.task_queued();
 
-            return (Some(process), app_flash_size, app_ram_size);
+            return (Ok(process), app_flash_size, memory_used);
         }
-        (None, 0, 0)
+        (Err(Error::InvalidHeader), 0, 0)
     }
 
     crate fn sbrk(&self, increment: isize) -> Result<*const u8, Error> {
@@ -754,31 +2023,91 @@ impl Process<'a> {
         } else {
             let old_break = self.app_break.get();
             self.app_break.set(new_break);
+            self.mpu_needs_update.set(true);
             Ok(old_break)
         }
     }
 
     /// Checks if the buffer represented by the passed in base pointer and size
-    /// are within the memory bounds currently exposed to the processes (i.e.
-    /// ending at `kernel_memory_break`. If this method returns true, the buffer
-    /// is guaranteed to be accessible to the process and to not overlap with
-    /// the grant region.
+    /// are within the memory the process owns, i.e. between the start of its
+    /// memory and its break (`app_break`). If this method returns true, the
+    /// buffer is guaranteed to be accessible to the process and to not
+    /// overlap with the grant region, or with the unallocated memory below it
+    /// that the grant region may grow into.
     crate fn in_exposed_bounds(&self, buf_start_addr: *const u8, size: usize) -> bool {
         let buf_end_addr = buf_start_addr.wrapping_offset(size as isize);
 
         buf_end_addr >= buf_start_addr
             && buf_start_addr >= self.mem_start()
-            && buf_end_addr <= self.mem_break()
+            && buf_end_addr <= self.app_break.get()
     }
 
-    crate unsafe fn alloc(&self, size: usize) -> Option<&mut [u8]> {
-        let new_break = self.kernel_memory_break.get().offset(-(size as isize));
-        if new_break < self.app_break.get() {
+    /// Read the word at `addr` in the memory the process can access, for
+    /// example so a debugging capsule can inspect a process. Returns `None` if
+    /// `addr` is not word aligned or the word is not entirely within the
+    /// process's accessible memory (see `in_exposed_bounds()`).
+    pub fn read_word(&self, addr: *const u8) -> Option<u32> {
+        if addr as usize % mem::size_of::<u32>() == 0
+            && self.in_exposed_bounds(addr, mem::size_of::<u32>())
+        {
+            Some(unsafe { read_volatile(addr as *const u32) })
+        } else {
             None
+        }
+    }
+
+    /// Zero the contents of the process's heap, `[app_heap_start, app_break)`,
+    /// without moving the break or restarting the process. This lets sensitive
+    /// data be cleared from RAM while the process keeps running.
+    ///
+    /// The kernel only knows where the heap starts if the process reported it
+    /// (see memop 11), so this returns `AddressOutOfBounds` if the heap start is
+    /// unknown.
+    pub fn zero_heap(&self) -> Result<(), Error> {
+        let heap_start = self
+            .debug
+            .map_or(None, |debug| debug.app_heap_start_pointer);
+        match heap_start {
+            None => Err(Error::AddressOutOfBounds),
+            Some(heap_start) => {
+                let heap_end = self.app_break.get();
+                if heap_start < heap_end {
+                    unsafe {
+                        ptr::write_bytes(
+                            heap_start as *mut u8,
+                            0,
+                            heap_end as usize - heap_start as usize,
+                        );
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Allocate `size` bytes by growing the grant region down. On failure this
+    /// says whether the allocation can never fit (`OutOfMemory`: it is larger
+    /// than all the memory below the grant region) or only does not fit now
+    /// (`HeapContended`: the process's heap is using the memory, and the
+    /// allocation would succeed if the process moved its break down).
+    crate unsafe fn alloc_result(&self, size: usize) -> Result<&mut [u8], Error> {
+        let kernel_break = self.kernel_memory_break.get() as usize;
+        let result = if size > kernel_break - self.mem_start() as usize {
+            Err(Error::OutOfMemory)
+        } else if kernel_break - size < self.app_break.get() as usize {
+            Err(Error::HeapContended)
         } else {
+            let new_break = (kernel_break - size) as *mut u8;
             self.kernel_memory_break.set(new_break);
-            Some(slice::from_raw_parts_mut(new_break as *mut u8, size))
+            self.mpu_needs_update.set(true);
+            Ok(slice::from_raw_parts_mut(new_break, size))
+        };
+        if result.is_err() {
+            self.debug.map(|debug| {
+                debug.grant_alloc_failures += 1;
+            });
         }
+        result
     }
 
     crate unsafe fn free<T>(&self, _: *mut T) {}
@@ -798,14 +2127,22 @@ impl Process<'a> {
         }
     }
 
+    /// The grant region `grant_num` of this process, or null if it has not
+    /// been allocated.
     crate unsafe fn grant_for<T>(&self, grant_num: usize) -> *mut T {
         *self.grant_ptr(grant_num)
     }
 
-    crate unsafe fn grant_for_or_alloc<T: Default>(&self, grant_num: usize) -> Option<*mut T> {
+    /// The grant region `grant_num` of this process, allocating it and
+    /// initializing it with `T::default()` if it has not been allocated yet.
+    /// Fails as `alloc_result()` does if there is no memory for it.
+    crate unsafe fn grant_for_or_alloc<T: Default>(
+        &self,
+        grant_num: usize,
+    ) -> Result<*mut T, Error> {
         let ctr_ptr = self.grant_ptr::<T>(grant_num);
         if (*ctr_ptr).is_null() {
-            self.alloc(mem::size_of::<T>()).map(|root_arr| {
+            self.alloc_result(mem::size_of::<T>()).map(|root_arr| {
                 let root_ptr = root_arr.as_mut_ptr() as *mut T;
                 // Initialize the grant contents using ptr::write, to
                 // ensure that we don't try to drop the contents of
@@ -816,17 +2153,32 @@ impl Process<'a> {
                 root_ptr
             })
         } else {
-            Some(*ctr_ptr)
+            Ok(*ctr_ptr)
         }
     }
 
+    /// Size in words of the exception frame the hardware stacked when the
+    /// process last entered the kernel.
+    fn stacked_frame_words(&self) -> isize {
+        #[cfg(feature = "fpu")]
+        {
+            // r0-r3, r12, lr, pc, xPSR, s0-s15, FPSCR and a reserved word.
+            if self.stored_regs.fp.extended_frame.get() != 0 {
+                return 26;
+            }
+        }
+        8
+    }
+
     crate fn pop_syscall_stack(&self) {
         let pspr = self.current_stack_pointer.get() as *const usize;
         unsafe {
             self.yield_pc.set(read_volatile(pspr.offset(6)));
             self.psr.set(read_volatile(pspr.offset(7)));
-            self.current_stack_pointer
-                .set((self.current_stack_pointer.get() as *mut usize).offset(8) as *mut u8);
+            self.current_stack_pointer.set(
+                (self.current_stack_pointer.get() as *mut usize).offset(self.stacked_frame_words())
+                    as *mut u8,
+            );
             self.debug.map(|debug| {
                 if self.current_stack_pointer.get() < debug.min_stack_pointer {
                     debug.min_stack_pointer = self.current_stack_pointer.get();
@@ -837,13 +2189,34 @@ impl Process<'a> {
 
     /// Context switch to the process.
     crate unsafe fn push_function_call(&self, callback: FunctionCall) {
+        // There must be room for a full exception frame (eight words) between
+        // the stack pointer and the start of process memory, otherwise pushing
+        // the frame would write below the memory the process owns. Treat this
+        // as a fault of the process rather than corrupting other memory.
+        let frame_size = 8 * mem::size_of::<usize>();
+        if (self.current_stack_pointer.get() as usize) < self.mem_start() as usize + frame_size {
+            self.fault_state();
+            return;
+        }
+        if !self.check_stack_alignment() {
+            return;
+        }
+
         self.kernel.increment_work();
 
         self.state.set(State::Running);
+        // The process was idle until now, so the time it spent waiting for
+        // this callback must not count against it in `check_watchdog()`.
+        let now = self.kernel.ticks();
+        self.debug.map(|debug| {
+            debug.last_syscall_time = now;
+        });
         // Fill in initial stack expected by SVC handler
         // Top minus 8 u32s for r0-r3, r12, lr, pc and xPSR
         let stack_bottom = (self.current_stack_pointer.get() as *mut usize).offset(-8);
         write_volatile(stack_bottom.offset(7), self.psr.get());
+        // `FunctionCall::new()` sets the Thumb bit, but the fields are public
+        // and a `FunctionCall` built directly may not have it.
         write_volatile(stack_bottom.offset(6), callback.pc | 1);
 
         // Set the LR register to the saved PC so the callback returns to
@@ -856,6 +2229,10 @@ impl Process<'a> {
         write_volatile(stack_bottom.offset(3), callback.r3);
 
         self.current_stack_pointer.set(stack_bottom as *mut u8);
+        // The frame just pushed is a basic frame, so the process must be
+        // returned to without floating point context.
+        #[cfg(feature = "fpu")]
+        self.stored_regs.fp.extended_frame.set(0);
         self.debug.map(|debug| {
             if self.current_stack_pointer.get() < debug.min_stack_pointer {
                 debug.min_stack_pointer = self.current_stack_pointer.get();
@@ -863,6 +2240,25 @@ impl Process<'a> {
         });
     }
 
+    /// ARM requires the stack pointer to be 8-byte aligned at exception
+    /// boundaries, which is where the kernel pushes and pops frames. A
+    /// misaligned stack pointer means the process corrupted it, so fault the
+    /// process rather than let it fail in some less obvious way later.
+    /// Returns `false` if the process was faulted.
+    unsafe fn check_stack_alignment(&self) -> bool {
+        let sp = self.current_stack_pointer.get() as usize;
+        if sp % 8 != 0 {
+            debug!(
+                "{} stack pointer {:#x} is not 8-byte aligned",
+                self.package_name, sp
+            );
+            self.fault_state();
+            false
+        } else {
+            true
+        }
+    }
+
     crate unsafe fn app_fault(&self) -> bool {
         read_volatile(&APP_FAULT) != 0
     }
@@ -871,13 +2267,47 @@ impl Process<'a> {
         read_volatile(&SYSCALL_FIRED) != 0
     }
 
+    /// Determine why the process last returned to the kernel. The arch
+    /// crate's SVC handler sets `SYSCALL_FIRED`, and its hard fault handler
+    /// sets both `SYSCALL_FIRED` and `APP_FAULT`. Any other exception (the
+    /// systick or a peripheral interrupt) sets neither.
+    crate unsafe fn context_switch_reason(&self) -> ContextSwitchReason {
+        if self.app_fault() {
+            ContextSwitchReason::Fault
+        } else if self.syscall_fired() {
+            ContextSwitchReason::SyscallFired
+        } else {
+            ContextSwitchReason::Interrupted
+        }
+    }
+
     /// Context switch to the process.
     crate unsafe fn switch_to(&self) {
         write_volatile(&mut SYSCALL_FIRED, 0);
-        let psp = switch_to_user(
-            self.current_stack_pointer.get(),
-            &*(&self.stored_regs as *const StoredRegs as *const [usize; 8]),
-        );
+        // If the process is faulted here the scheduler sees that it did not
+        // make a syscall, and moves on.
+        if !self.check_stack_alignment() {
+            return;
+        }
+        // On ARMv8-M the hardware faults the process as soon as its stack
+        // grows below the start of its memory, instead of letting it write
+        // over whatever is below.
+        #[cfg(feature = "armv8m")]
+        set_process_stack_limit(self.mem_start());
+        let psp = switch_to_user(self.current_stack_pointer.get(), &self.stored_regs);
+        // The kernel reads syscall arguments through the stack pointer, so a
+        // process that moved it outside its own memory must not get that far.
+        // Report it to the scheduler as a fault, like the hard fault handler
+        // does, and keep the last good stack pointer for debugging.
+        if psp < self.mem_start() as *mut u8 || psp >= self.mem_end() as *mut u8 {
+            debug!(
+                "{} stack pointer {:#x} is outside its memory",
+                self.package_name, psp as usize
+            );
+            write_volatile(&mut SYSCALL_FIRED, 1);
+            write_volatile(&mut APP_FAULT, 1);
+            return;
+        }
         self.current_stack_pointer.set(psp);
         self.debug.map(|debug| {
             if self.current_stack_pointer.get() < debug.min_stack_pointer {
@@ -886,6 +2316,11 @@ impl Process<'a> {
         });
     }
 
+    /// Decode the syscall the process made from the `svc` instruction just
+    /// before its stacked PC. `svc` is a 16-bit Thumb instruction with the
+    /// number in its low byte on both ARMv6-M (Cortex-M0/M0+) and ARMv7-M,
+    /// and both stack the same exception frame, so this works for all
+    /// Cortex-M arch crates.
     crate fn svc_number(&self) -> Option<Syscall> {
         let psp = self.current_stack_pointer.get() as *const *const u16;
         unsafe {
@@ -903,13 +2338,74 @@ impl Process<'a> {
         }
     }
 
-    crate fn incr_syscall_count(&self) {
+    /// How many syscalls the process has made since it (re)started.
+    crate fn syscall_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.syscall_count)
+    }
+
+    /// Count a syscall made by the process at kernel tick `now`.
+    crate fn incr_syscall_count(&self, now: u64) {
+        self.has_run.set(true);
         self.debug.map(|debug| {
             debug.syscall_count += 1;
             debug.last_syscall = self.svc_number();
+            debug.last_syscall_time = now;
+        });
+    }
+
+    /// Append a word to the value the app is staging. Returns `false` if the
+    /// staging buffer is already full.
+    crate fn stage_word(&self, word: usize) -> bool {
+        self.staged_words.map_or(false, |staged| {
+            if staged.staging_len >= STAGED_WORDS_MAX {
+                false
+            } else {
+                staged.staging[staged.staging_len] = word;
+                staged.staging_len += 1;
+                true
+            }
+        })
+    }
+
+    /// Make the staged words available to the kernel, replacing any
+    /// previously committed value that has not been taken yet. Returns how
+    /// many words were committed, or `None` if nothing was staged.
+    crate fn commit_staged_words(&self) -> Option<usize> {
+        self.staged_words.map_or(None, |staged| {
+            if staged.staging_len == 0 {
+                None
+            } else {
+                let len = staged.staging_len;
+                staged.committed = staged.staging;
+                staged.committed_len = Some(len);
+                staged.staging_len = 0;
+                Some(len)
+            }
+        })
+    }
+
+    /// Throw away words that have been staged but not committed.
+    crate fn discard_staged_words(&self) {
+        self.staged_words.map(|staged| {
+            staged.staging_len = 0;
         });
     }
 
+    /// Copy the value the app most recently committed through memop into
+    /// `buf` and clear it, returning how many words it had. Returns `None` if
+    /// the app has not committed a value since it was last taken. If `buf` is
+    /// shorter than the value only the first `buf.len()` words are copied.
+    pub fn take_committed_words(&self, buf: &mut [usize]) -> Option<usize> {
+        self.staged_words.map_or(None, |staged| {
+            staged.committed_len.take().map(|len| {
+                for (dest, word) in buf.iter_mut().zip(staged.committed[..len].iter()) {
+                    *dest = *word;
+                }
+                len
+            })
+        })
+    }
+
     crate fn sp(&self) -> usize {
         self.current_stack_pointer.get() as usize
     }
@@ -934,11 +2430,36 @@ impl Process<'a> {
         self.set_r0(r);
     }
 
+    /// Pass the result of a `command` syscall back to the process: the return
+    /// code in `r0` and, if there is one, the value in `r1`.
+    crate fn set_command_return(&self, ret: CommandReturn) {
+        match ret.value {
+            Some(value) => {
+                let code = isize::from(ret.code) as u32;
+                self.set_return_value_u64(u64::from(value) << 32 | u64::from(code));
+            }
+            None => self.set_return_code(ret.code),
+        }
+    }
+
     crate fn set_r0(&self, val: isize) {
         let pspr = self.current_stack_pointer.get() as *mut isize;
         unsafe { write_volatile(pspr, val) }
     }
 
+    /// Set a 64-bit return value for the syscall. The low word is written to
+    /// `r0` and the high word to `r1` in the stacked exception frame, so that
+    /// userspace can read the pair back as a single 64-bit value.
+    pub fn set_return_value_u64(&self, value: u64) {
+        self.set_r0(value as u32 as isize);
+        self.set_r1((value >> 32) as u32 as isize);
+    }
+
+    crate fn set_r1(&self, val: isize) {
+        let pspr = self.current_stack_pointer.get() as *mut isize;
+        unsafe { write_volatile(pspr.offset(1), val) }
+    }
+
     crate fn r1(&self) -> usize {
         let pspr = self.current_stack_pointer.get() as *const usize;
         unsafe { read_volatile(pspr.offset(1)) }
@@ -954,6 +2475,14 @@ impl Process<'a> {
         unsafe { read_volatile(pspr.offset(3)) }
     }
 
+    /// The process's `r4` as it was when it last entered the kernel. Unlike
+    /// `r0`-`r3` it is not part of the stacked exception frame, but is saved
+    /// with the other callee-saved registers on every switch out of the
+    /// process.
+    crate fn r4(&self) -> usize {
+        self.stored_regs.r4
+    }
+
     crate fn r12(&self) -> usize {
         let pspr = self.current_stack_pointer.get() as *const usize;
         unsafe { read_volatile(pspr.offset(4)) }
@@ -964,185 +2493,96 @@ impl Process<'a> {
         unsafe { read_volatile(pspr.offset(7)) }
     }
 
-    crate unsafe fn fault_str<W: Write>(&self, writer: &mut W) {
-        let _ccr = SCB_REGISTERS[0];
-        let cfsr = SCB_REGISTERS[1];
-        let hfsr = SCB_REGISTERS[2];
-        let mmfar = SCB_REGISTERS[3];
-        let bfar = SCB_REGISTERS[4];
-
-        let iaccviol = (cfsr & 0x01) == 0x01;
-        let daccviol = (cfsr & 0x02) == 0x02;
-        let munstkerr = (cfsr & 0x08) == 0x08;
-        let mstkerr = (cfsr & 0x10) == 0x10;
-        let mlsperr = (cfsr & 0x20) == 0x20;
-        let mmfarvalid = (cfsr & 0x80) == 0x80;
-
-        let ibuserr = ((cfsr >> 8) & 0x01) == 0x01;
-        let preciserr = ((cfsr >> 8) & 0x02) == 0x02;
-        let impreciserr = ((cfsr >> 8) & 0x04) == 0x04;
-        let unstkerr = ((cfsr >> 8) & 0x08) == 0x08;
-        let stkerr = ((cfsr >> 8) & 0x10) == 0x10;
-        let lsperr = ((cfsr >> 8) & 0x20) == 0x20;
-        let bfarvalid = ((cfsr >> 8) & 0x80) == 0x80;
-
-        let undefinstr = ((cfsr >> 16) & 0x01) == 0x01;
-        let invstate = ((cfsr >> 16) & 0x02) == 0x02;
-        let invpc = ((cfsr >> 16) & 0x04) == 0x04;
-        let nocp = ((cfsr >> 16) & 0x08) == 0x08;
-        let unaligned = ((cfsr >> 16) & 0x100) == 0x100;
-        let divbysero = ((cfsr >> 16) & 0x200) == 0x200;
-
-        let vecttbl = (hfsr & 0x02) == 0x02;
-        let forced = (hfsr & 0x40000000) == 0x40000000;
-
-        let _ = writer.write_fmt(format_args!("\r\n---| Fault Status |---\r\n"));
-
-        if iaccviol {
-            let _ = writer.write_fmt(format_args!(
-                "Instruction Access Violation:       {}\r\n",
-                iaccviol
-            ));
-        }
-        if daccviol {
-            let _ = writer.write_fmt(format_args!(
-                "Data Access Violation:              {}\r\n",
-                daccviol
-            ));
-        }
-        if munstkerr {
-            let _ = writer.write_fmt(format_args!(
-                "Memory Management Unstacking Fault: {}\r\n",
-                munstkerr
-            ));
-        }
-        if mstkerr {
-            let _ = writer.write_fmt(format_args!(
-                "Memory Management Stacking Fault:   {}\r\n",
-                mstkerr
-            ));
-        }
-        if mlsperr {
-            let _ = writer.write_fmt(format_args!(
-                "Memory Management Lazy FP Fault:    {}\r\n",
-                mlsperr
-            ));
-        }
+    /// Return how many times this process has faulted and been restarted by
+    /// the kernel.
+    pub fn get_restart_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.restart_count)
+    }
 
-        if ibuserr {
-            let _ = writer.write_fmt(format_args!(
-                "Instruction Bus Error:              {}\r\n",
-                ibuserr
-            ));
-        }
-        if preciserr {
-            let _ = writer.write_fmt(format_args!(
-                "Precise Data Bus Error:             {}\r\n",
-                preciserr
-            ));
-        }
-        if impreciserr {
-            let _ = writer.write_fmt(format_args!(
-                "Imprecise Data Bus Error:           {}\r\n",
-                impreciserr
-            ));
-        }
-        if unstkerr {
-            let _ = writer.write_fmt(format_args!(
-                "Bus Unstacking Fault:               {}\r\n",
-                unstkerr
-            ));
-        }
-        if stkerr {
-            let _ = writer.write_fmt(format_args!(
-                "Bus Stacking Fault:                 {}\r\n",
-                stkerr
-            ));
-        }
-        if lsperr {
-            let _ = writer.write_fmt(format_args!(
-                "Bus Lazy FP Fault:                  {}\r\n",
-                lsperr
-            ));
-        }
+    /// The kernel tick (see `Kernel::ticks()`) at which the process last made
+    /// a syscall, or at which it (re)started or was last resumed to run a
+    /// callback, if that was later. A running process that has not made a
+    /// syscall for a long time may be hung.
+    pub fn last_syscall_time(&self) -> u64 {
+        self.debug.map_or(0, |debug| debug.last_syscall_time)
+    }
 
-        if undefinstr {
-            let _ = writer.write_fmt(format_args!(
-                "Undefined Instruction Usage Fault:  {}\r\n",
-                undefinstr
-            ));
-        }
-        if invstate {
-            let _ = writer.write_fmt(format_args!(
-                "Invalid State Usage Fault:          {}\r\n",
-                invstate
-            ));
-        }
-        if invpc {
-            let _ = writer.write_fmt(format_args!(
-                "Invalid PC Load Usage Fault:        {}\r\n",
-                invpc
-            ));
-        }
-        if nocp {
-            let _ = writer.write_fmt(format_args!(
-                "No Coprocessor Usage Fault:         {}\r\n",
-                nocp
-            ));
-        }
-        if unaligned {
-            let _ = writer.write_fmt(format_args!(
-                "Unaligned Access Usage Fault:       {}\r\n",
-                unaligned
-            ));
-        }
-        if divbysero {
-            let _ = writer.write_fmt(format_args!(
-                "Divide By Zero:                     {}\r\n",
-                divbysero
-            ));
-        }
+    /// Return how many IPC notifications to this process have been dropped
+    /// because its task queue was full since it last (re)started.
+    pub fn get_dropped_ipc_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.dropped_ipc_count)
+    }
 
-        if vecttbl {
-            let _ = writer.write_fmt(format_args!(
-                "Bus Fault on Vector Table Read:     {}\r\n",
-                vecttbl
-            ));
-        }
-        if forced {
-            let _ = writer.write_fmt(format_args!(
-                "Forced Hard Fault:                  {}\r\n",
-                forced
-            ));
-        }
+    /// Return how many grant allocations for this process have failed for
+    /// lack of memory since it last (re)started.
+    pub fn get_grant_alloc_failures(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.grant_alloc_failures)
+    }
 
-        if mmfarvalid {
-            let _ = writer.write_fmt(format_args!(
-                "Faulting Memory Address:            {:#010X}\r\n",
-                mmfar
-            ));
-        }
-        if bfarvalid {
-            let _ = writer.write_fmt(format_args!(
-                "Bus Fault Address:                  {:#010X}\r\n",
-                bfar
-            ));
-        }
+    /// Clear the restart count, for example once a process has been running
+    /// without faulting for a long enough time to be considered stable again.
+    pub fn reset_restart_count(&self) {
+        self.debug.map(|debug| {
+            debug.restart_count = 0;
+        });
+    }
 
-        if cfsr == 0 && hfsr == 0 {
-            let _ = writer.write_fmt(format_args!("No faults detected.\r\n"));
-        } else {
-            let _ = writer.write_fmt(format_args!(
-                "Fault Status Register (CFSR):       {:#010X}\r\n",
-                cfsr
-            ));
-            let _ = writer.write_fmt(format_args!(
-                "Hard Fault Status Register (HFSR):  {:#010X}\r\n",
-                hfsr
-            ));
+    /// The process's program counter when it last faulted, if it ever has.
+    pub fn last_fault_pc(&self) -> Option<usize> {
+        self.debug.map_or(None, |debug| debug.last_fault_pc)
+    }
+
+    /// The process's link register when it last faulted, if it ever has.
+    pub fn last_fault_lr(&self) -> Option<usize> {
+        self.debug.map_or(None, |debug| debug.last_fault_lr)
+    }
+
+    /// The process's stack pointer when it last faulted, if it ever has.
+    pub fn last_fault_sp(&self) -> Option<usize> {
+        self.debug.map_or(None, |debug| debug.last_fault_sp)
+    }
+
+    /// Route this process's debugging output (`statistics_str` and
+    /// `fault_str`) to its own sink rather than the console shared by all
+    /// processes. The sink must be synchronous as it is used when panicking.
+    pub fn set_debug_sink(&self, sink: &'static mut Write) {
+        self.debug_sink.replace(sink);
+    }
+
+    /// Call `fun` with the writer this process's debugging output should go
+    /// to: the sink registered with `set_debug_sink()` if there is one,
+    /// otherwise `default`.
+    crate fn with_debug_writer<W: Write, F>(&self, default: &mut W, fun: F)
+    where
+        F: FnOnce(&mut Write),
+    {
+        match self.debug_sink.take() {
+            Some(sink) => {
+                fun(sink);
+                self.debug_sink.replace(sink);
+            }
+            None => fun(default),
         }
     }
 
+    #[cfg(feature = "armv6m")]
+    crate unsafe fn fault_str<W: Write>(&self, writer: &mut W) {
+        let _ = writer.write_fmt(format_args!(
+            "\r\n---| Fault Status |---\r\nNot available on ARMv6-M\r\n"
+        ));
+    }
+
+    #[cfg(not(feature = "armv6m"))]
+    crate unsafe fn fault_str<W: Write>(&self, writer: &mut W) {
+        let status = FaultStatus {
+            cfsr: SCB_REGISTERS[1],
+            hfsr: SCB_REGISTERS[2],
+            mmfar: SCB_REGISTERS[3],
+            bfar: SCB_REGISTERS[4],
+        };
+        let _ = writer.write_fmt(format_args!("\r\n---| Fault Status |---\r\n{}", status));
+    }
+
     crate unsafe fn statistics_str<W: Write>(&self, writer: &mut W) {
         // Flash
         let flash_end = self.flash.as_ptr().offset(self.flash.len() as isize) as usize;
@@ -1150,22 +2590,17 @@ impl Process<'a> {
         let flash_protected_size = self.header.get_protected_size() as usize;
         let flash_app_start = flash_start + flash_protected_size;
         let flash_app_size = flash_end - flash_app_start;
-        let flash_init_fn = flash_start + self.header.get_init_function_offset() as usize;
+        let flash_init_fn = self.init_fn();
 
         // SRAM addresses
-        let sram_end = self.memory.as_ptr().offset(self.memory.len() as isize) as usize;
-        let sram_grant_start = self.kernel_memory_break.get() as usize;
-        let sram_heap_end = self.app_break.get() as usize;
-        let sram_heap_start = self.debug.map_or(ptr::null(), |debug| {
-            debug.app_heap_start_pointer.unwrap_or(ptr::null())
-        }) as usize;
-        let sram_stack_start = self.debug.map_or(ptr::null(), |debug| {
-            debug.app_stack_start_pointer.unwrap_or(ptr::null())
-        }) as usize;
-        let sram_stack_bottom =
-            self.debug
-                .map_or(ptr::null(), |debug| debug.min_stack_pointer) as usize;
-        let sram_start = self.memory.as_ptr() as usize;
+        let memory_map = self.memory_regions();
+        let sram_end = memory_map.sram_end;
+        let sram_grant_start = memory_map.grant_start;
+        let sram_heap_end = memory_map.app_break;
+        let sram_heap_start = memory_map.heap_start;
+        let sram_stack_start = memory_map.stack_top;
+        let sram_stack_bottom = memory_map.stack_bottom;
+        let sram_start = memory_map.sram_start;
 
         // SRAM sizes
         let sram_grant_size = sram_end - sram_grant_start;
@@ -1192,11 +2627,13 @@ impl Process<'a> {
         }
 
         // application statistics
-        let events_queued = self.tasks.map_or(0, |tasks| tasks.len());
-        let syscall_count = self.debug.map_or(0, |debug| debug.syscall_count);
+        let events_queued = self.tasks_len();
+        let syscall_count = self.syscall_count();
         let last_syscall = self.debug.map(|debug| debug.last_syscall);
         let dropped_callback_count = self.debug.map_or(0, |debug| debug.dropped_callback_count);
+        let dropped_ipc_count = self.get_dropped_ipc_count();
         let restart_count = self.debug.map_or(0, |debug| debug.restart_count);
+        let grant_alloc_failures = self.get_grant_alloc_failures();
 
         // register values
         let (r0, r1, r2, r3, r12, sp, lr, pc, xpsr) = (
@@ -1215,19 +2652,25 @@ impl Process<'a> {
             "\
              App: {}   -   [{:?}]\
              \r\n Events Queued: {}   Syscall Count: {}   Dropped Callback Count: {}\
-             \n Restart Count: {}\n",
+             \n Dropped IPC Count: {}   Restart Count: {}   Grant Alloc Failures: {}\n",
             self.package_name,
             self.state,
             events_queued,
             syscall_count,
             dropped_callback_count,
+            dropped_ipc_count,
             restart_count,
+            grant_alloc_failures,
         ));
 
         let _ = match last_syscall {
             Some(syscall) => writer.write_fmt(format_args!(" Last Syscall: {:?}", syscall)),
             None => writer.write_fmt(format_args!(" Last Syscall: None")),
         };
+        let _ = writer.write_fmt(format_args!(
+            "   Last Syscall Tick: {}",
+            self.last_syscall_time()
+        ));
 
         let _ = writer.write_fmt(format_args!("\
 \r\n\
@@ -1330,4 +2773,50 @@ impl Process<'a> {
             "\r\n in the app's folder and open the .lst file.\r\n\r\n"
         ));
     }
+
+    /// Write a complete crash report for this process: its statistics,
+    /// memory map and registers (`statistics_str`) followed by the fault
+    /// status registers (`fault_str`). Panic handlers can call this instead
+    /// of the individual printers so every board prints them in the same
+    /// order.
+    ///
+    /// **NOTE:** The supplied `writer` must be synchronous.
+    pub unsafe fn print_full_state<W: Write>(&self, writer: &mut W) {
+        self.statistics_str(writer);
+        self.fault_str(writer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnshareTable;
+
+    #[test]
+    fn unshare_while_held_invalidates_slice() {
+        let table = UnshareTable::new();
+        let bucket = UnshareTable::bucket(0x40001, 0);
+
+        // A capsule holds a slice from the process's first ALLOW.
+        assert!(table.is_shared(bucket, 1));
+
+        // The process unshares the buffer with its second ALLOW call.
+        table.unshared(bucket, 2);
+        assert!(!table.is_shared(bucket, 1));
+
+        // A buffer allowed again afterwards is shared.
+        assert!(table.is_shared(bucket, 3));
+
+        table.reset();
+        assert!(table.is_shared(bucket, 1));
+    }
+
+    #[test]
+    fn unshare_leaves_other_buckets_shared() {
+        let table = UnshareTable::new();
+        let bucket = UnshareTable::bucket(0x40001, 0);
+        let other = (bucket + 1) % super::UNSHARE_BUCKETS;
+
+        table.unshared(bucket, 2);
+        assert!(table.is_shared(other, 1));
+    }
 }
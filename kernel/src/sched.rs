@@ -1,12 +1,15 @@
 //! Tock core scheduler.
 
 use core::cell::Cell;
+use core::fmt::Write;
 use core::ptr;
 use core::ptr::NonNull;
 
 use callback;
 use callback::{AppId, Callback};
-use common::cells::NumericCellExt;
+use common::cells::{MapCell, NumericCellExt};
+use common::{Queue, RingBuffer};
+use grant;
 use grant::Grant;
 use ipc;
 use mem::AppSlice;
@@ -15,22 +18,90 @@ use platform::mpu::MPU;
 use platform::systick::SysTick;
 use platform::{Chip, Platform};
 use process;
-use process::{Process, Task};
+use process::{
+    AppVerifier, ContextSwitchReason, FaultResponse, MemoryAllocation, Process, ProcessObserver,
+    RestartCounterStore, Task,
+};
 use returncode::ReturnCode;
-use syscall::Syscall;
+use syscall::{CommandReturn, Syscall, SyscallLogger};
+use tbfheader;
 
 /// The time a process is permitted to run before being pre-empted
 const KERNEL_TICK_DURATION_US: u32 = 10000;
 /// Skip re-scheduling a process if its quanta is nearly exhausted
 const MIN_QUANTA_THRESHOLD_US: u32 = 500;
+/// How many grants `Kernel::preallocate_grants()` can allocate. Grants created
+/// after this many are still allocated on first use.
+const MAX_PREALLOCATED_GRANTS: usize = 32;
+
+/// Allocates one grant for a process, see `grant::allocate_grant()`.
+type GrantAllocator = unsafe fn(&Process, usize) -> Result<(), process::Error>;
+
+/// How the kernel picks which process to run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SchedulingPolicy {
+    /// Each pass over the processes array runs every process with work to
+    /// do, in slot order, for up to one timeslice. A process may ask for
+    /// another one to be run first on the next pass (see memop 15).
+    RoundRobin,
+    /// Each time the kernel picks a process, it runs the one with the
+    /// earliest deadline among those with work to do, for up to one
+    /// timeslice. Processes set their deadline with memop 16. Processes
+    /// without a deadline run only when no process with one has work, and
+    /// ties go to the process in the lowest slot. Deadlines are compared as
+    /// plain numbers, so processes must agree on a time base that does not
+    /// wrap.
+    EarliestDeadlineFirst,
+    /// Like round robin, each pass runs every process with work to do for up
+    /// to one timeslice, but processes with a higher priority run before
+    /// those with a lower one. Processes with the same priority run in slot
+    /// order, so with the default of equal priorities this is round robin.
+    /// Priorities are set by the board or with memop 18.
+    Priority,
+}
+
+/// Kinds of scheduling events recorded in the kernel event log.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KernelEventKind {
+    /// The kernel switched to the process.
+    Scheduled,
+    /// The process called into the kernel with a syscall.
+    Syscall,
+    /// The process was preempted by an interrupt or the end of its
+    /// timeslice.
+    Preempted,
+    /// The process called `yield`.
+    Yielded,
+    /// The process faulted.
+    Faulted,
+    /// The process was restarted after a fault.
+    Restarted,
+}
+
+/// An entry in the kernel event log.
+#[derive(Copy, Clone, Debug)]
+pub struct KernelEvent {
+    pub kind: KernelEventKind,
+    /// Slot of the process in the processes array, as in `AppId::idx()`.
+    pub process_index: usize,
+    /// Kernel ticks elapsed when the event happened.
+    pub tick: u64,
+}
 
 /// Main object for the kernel. Each board will need to create one.
 pub struct Kernel {
     /// How many "to-do" items exist at any given time. These include
     /// outstanding callbacks and processes in the Running state.
     work: Cell<usize>,
-    /// This holds a pointer to the static array of Process pointers.
+    /// This holds a pointer to the static array of Process pointers. The
+    /// processes are only ever reached through shared references: all of
+    /// their mutable state is in `Cell`s and `MapCell`s, so no `&mut Process`
+    /// is handed out.
     processes: &'static [Option<&'static Process<'static>>],
+    /// Slots for processes loaded while the kernel is running, numbered after
+    /// those in `processes`. Empty unless the board provides them with
+    /// `set_runtime_process_slots()`.
+    runtime_slots: Cell<&'static [Cell<Option<&'static Process<'static>>>]>,
     /// How many grant regions have been setup. This is incremented on every
     /// call to `create_grant()`. We need to explicitly track this so that when
     /// processes are created they can allocated pointers for each grant.
@@ -40,6 +111,52 @@ pub struct Kernel {
     /// created and the data structures for grants have already been
     /// established.
     grants_finalized: Cell<bool>,
+    /// For each grant, by grant number, the function that allocates it for a
+    /// process. Used to allocate all of a process's grants up front.
+    grant_allocators: Cell<[Option<GrantAllocator>; MAX_PREALLOCATED_GRANTS]>,
+    /// How many times the kernel has switched to a process since boot. This
+    /// is a `u64` so that it does not wrap in any realistic uptime, even
+    /// though it takes two words to store on 32-bit platforms.
+    context_switches: Cell<u64>,
+    /// Maximum number of syscalls a process may make in a single timeslice
+    /// before the kernel moves on to the next process. `None` means there is no
+    /// limit.
+    syscall_budget: Cell<Option<usize>>,
+    /// Source of process generations. Each time a process is created or
+    /// restarted it takes the next value, so that `AppId`s for an earlier
+    /// process in the same slot can be told apart.
+    process_generation: Cell<usize>,
+    /// A process that a process asked to be run next, e.g. an IPC service
+    /// handing control straight back to its client. It is honored at the
+    /// start of the next pass over the processes if the process is runnable.
+    next_process_hint: Cell<Option<usize>>,
+    /// The nearest deadline of any pending software timer, if one was
+    /// registered. The kernel passes it to the chip when going to sleep.
+    next_wakeup: Cell<Option<u32>>,
+    /// Optional black-box recorder of recent scheduling events. Once full, the
+    /// oldest events are overwritten.
+    event_log: MapCell<RingBuffer<'static, KernelEvent>>,
+    /// Optionally notified when a process faults or is restarted.
+    process_observer: Cell<Option<&'static ProcessObserver>>,
+    /// Optionally told about every syscall, for tracing.
+    syscall_logger: Cell<Option<&'static SyscallLogger>>,
+    /// Optionally persists how often each process was restarted after a
+    /// fault.
+    restart_counter_store: Cell<Option<&'static RestartCounterStore>>,
+    /// Optionally checks the signatures of processes as they are loaded.
+    app_verifier: Cell<Option<&'static AppVerifier>>,
+    /// How many kernel ticks have elapsed, counted as the number of process
+    /// timeslices that ran until the systick expired. This only advances while
+    /// processes are using the CPU, which is when a stalled process shows up.
+    ticks: Cell<u64>,
+    /// How the kernel picks which process to run.
+    scheduling_policy: Cell<SchedulingPolicy>,
+    /// Whether any process fault panics the kernel, whatever the process's
+    /// `FaultResponse`.
+    panic_on_fault: Cell<bool>,
+    /// Whether processes loaded from now on have their memory painted, so
+    /// their stack usage can be measured.
+    stack_painting: Cell<bool>,
 }
 
 impl Kernel {
@@ -47,28 +164,375 @@ impl Kernel {
         Kernel {
             work: Cell::new(0),
             processes: processes,
+            runtime_slots: Cell::new(&[]),
             grant_counter: Cell::new(0),
             grants_finalized: Cell::new(false),
+            grant_allocators: Cell::new([None; MAX_PREALLOCATED_GRANTS]),
+            context_switches: Cell::new(0),
+            syscall_budget: Cell::new(None),
+            process_generation: Cell::new(0),
+            next_process_hint: Cell::new(None),
+            next_wakeup: Cell::new(None),
+            event_log: MapCell::empty(),
+            process_observer: Cell::new(None),
+            restart_counter_store: Cell::new(None),
+            app_verifier: Cell::new(None),
+            syscall_logger: Cell::new(None),
+            ticks: Cell::new(0),
+            scheduling_policy: Cell::new(SchedulingPolicy::RoundRobin),
+            panic_on_fault: Cell::new(false),
+            stack_painting: Cell::new(false),
         }
     }
 
+    /// How many kernel ticks have elapsed since boot. A tick is a process
+    /// timeslice that ran until the systick expired, so this only advances
+    /// while processes are using the CPU.
+    pub fn ticks(&self) -> u64 {
+        self.ticks.get()
+    }
+
     /// Something was scheduled for a process, so there is more work to do.
     crate fn increment_work(&self) {
         self.work.increment();
     }
 
+    /// How many units of work processes have outstanding.
+    crate fn work(&self) -> usize {
+        self.work.get()
+    }
+
     /// Something finished for a process, so we decrement how much work there is
     /// to do.
+    ///
+    /// In debug builds this panics if there is no work left, so that a
+    /// mismatched decrement is caught where it happens rather than showing up
+    /// later as the kernel spinning or sleeping at the wrong time.
     crate fn decrement_work(&self) {
+        debug_assert!(
+            self.work.get() > 0,
+            "Kernel work counter decremented below zero"
+        );
         self.work.decrement();
     }
 
     /// Helper function for determining if we should service processes or go to
-    /// sleep.
-    fn processes_blocked(&self) -> bool {
+    /// sleep. Returns `true` when no process has outstanding work (the work
+    /// counter is zero), so boards with their own main loop can use it to
+    /// decide when to enter a low-power state.
+    pub fn processes_blocked(&self) -> bool {
         self.work.get() == 0
     }
 
+    /// Return the policy the kernel schedules processes with, so diagnostics
+    /// and capsules can report it.
+    pub fn scheduling_policy(&self) -> SchedulingPolicy {
+        self.scheduling_policy.get()
+    }
+
+    /// Change how the kernel picks which process to run. The default is
+    /// `SchedulingPolicy::RoundRobin`.
+    pub fn set_scheduling_policy(&self, policy: SchedulingPolicy) {
+        self.scheduling_policy.set(policy);
+    }
+
+    /// Make any process fault panic the kernel, overriding the
+    /// `FaultResponse` of every process, e.g. so that faults are not missed
+    /// while developing apps. Off by default, in which case each process's
+    /// `FaultResponse` applies.
+    pub fn set_panic_on_fault(&self, panic_on_fault: bool) {
+        self.panic_on_fault.set(panic_on_fault);
+    }
+
+    crate fn panic_on_fault(&self) -> bool {
+        self.panic_on_fault.get()
+    }
+
+    /// Fill the memory of processes loaded from now on with a known pattern
+    /// when they are created and restarted, so that
+    /// `Process::stack_high_water()` can report how much stack they use. This
+    /// costs time at load and restart, so it is off by default. Boards enable
+    /// it before calling `load_processes()`.
+    pub fn set_stack_painting(&self, enabled: bool) {
+        self.stack_painting.set(enabled);
+    }
+
+    crate fn stack_painting(&self) -> bool {
+        self.stack_painting.get()
+    }
+
+    /// The highest priority of any process below `below`, or of any process
+    /// at all if `below` is `None`.
+    fn next_priority_level(&self, below: Option<u8>) -> Option<u8> {
+        self.process_slots()
+            .filter_map(|p| p.map(|process| process.priority()))
+            .filter(|&priority| below.map_or(true, |below| priority < below))
+            .max()
+    }
+
+    /// Find the process with work to do that has the earliest deadline,
+    /// preferring processes with a deadline over those without, and lower
+    /// slots over higher ones.
+    fn earliest_deadline_process(&self) -> Option<usize> {
+        self.process_slots()
+            .enumerate()
+            .filter_map(|(i, p)| {
+                p.and_then(|process| match process.not_runnable_reason() {
+                    None => Some((i, process.deadline())),
+                    Some(_) => None,
+                })
+            })
+            .min_by_key(|&(_, deadline)| (deadline.is_none(), deadline))
+            .map(|(i, _)| i)
+    }
+
+    /// Return the total number of context switches into processes since the
+    /// kernel started. Combined with uptime this gives the switch rate.
+    pub fn total_context_switches(&self) -> u64 {
+        self.context_switches.get()
+    }
+
+    /// Limit how many syscalls a process can make in one timeslice. Once a
+    /// process has made `budget` syscalls the kernel stops servicing it and
+    /// moves to the next process, even if it still has time left in its
+    /// quantum. This keeps an app spinning on `command` from starving others.
+    /// Passing `None` removes the limit, which is the default.
+    pub fn set_syscall_budget(&self, budget: Option<usize>) {
+        self.syscall_budget.set(budget);
+    }
+
+    /// Return the total number of tasks (callbacks and IPC notifications)
+    /// queued across all processes. Drivers that generate many events can use
+    /// this to decide whether to throttle.
+    pub fn total_queued_tasks(&self) -> usize {
+        self.process_slots()
+            .map(|process| process.map_or(0, |p| p.tasks_len()))
+            .sum()
+    }
+
+    /// Return the slots of processes that are still running but have not made
+    /// a single syscall, once at least `min_ticks` kernel ticks have elapsed.
+    /// Such a process may be stuck in its startup code (e.g. static
+    /// initialization) or spinning after failing silently. Before `min_ticks`
+    /// have passed nothing is reported, as processes may legitimately not
+    /// have reached their first syscall yet.
+    pub fn stalled_in_startup(&self, min_ticks: u64) -> impl Iterator<Item = usize> + '_ {
+        let uptime_reached = self.ticks.get() >= min_ticks;
+        self.process_slots()
+            .enumerate()
+            .filter_map(move |(i, process)| match process {
+                Some(p)
+                    if uptime_reached
+                        && p.current_state() == process::State::Running
+                        && p.syscall_count() == 0 =>
+                {
+                    Some(i)
+                }
+                _ => None,
+            })
+    }
+
+    /// Fault every running process that has not made a syscall for more than
+    /// `max_gap` kernel ticks before `now`, and return how many were
+    /// faulted. Each faulted process is then restarted or panics the
+    /// kernel according to its `FaultResponse`.
+    ///
+    /// A board calls this periodically, e.g. from an alarm, usually with
+    /// `now` set to `ticks()`. It is meant to catch a process that is stuck
+    /// in a callback without ever returning to `yield`. Such a process keeps
+    /// being scheduled for whole timeslices but never makes a syscall.
+    /// Processes that have yielded are idle, not hung, so they are never
+    /// faulted however long they wait, even once a callback is queued for
+    /// them. The gap is counted from when a process was last resumed to run
+    /// a callback if that was after its last syscall. Note that
+    /// kernel ticks only advance while processes use up their timeslices,
+    /// so `max_gap` measures CPU time taken by processes, not wall time.
+    pub fn check_watchdog(&self, now: u64, max_gap: u64) -> usize {
+        let mut faulted = 0;
+        for process in self.process_slots().filter_map(|p| p) {
+            if process.current_state() == process::State::Running
+                && now.saturating_sub(process.last_syscall_time()) > max_gap
+            {
+                debug!(
+                    "{} made no syscall for {} ticks, faulting it",
+                    process.package_name,
+                    now - process.last_syscall_time()
+                );
+                unsafe {
+                    process.fault_state();
+                }
+                faulted += 1;
+            }
+        }
+        faulted
+    }
+
+    /// Restart every process from its init function, whatever state it is
+    /// in, e.g. to relaunch apps after updating them without resetting the
+    /// chip. Each process is reset the same way as a faulted process with
+    /// `FaultResponse::Restart`. Must not be called while a process is being
+    /// serviced, i.e. only from board code or a capsule callback.
+    pub fn reset_all_processes(&self) {
+        for process in self.process_slots().filter_map(|p| p) {
+            unsafe {
+                process.restart();
+            }
+        }
+    }
+
+    /// Call `closure` on every process that has faulted and was not
+    /// restarted, e.g. because its fault response is `FaultResponse::Stop`
+    /// or it faulted before its first syscall. This lets a board or capsule
+    /// log or recover crashed apps without checking each process's state
+    /// itself.
+    pub fn for_each_fault<F>(&'static self, mut closure: F)
+    where
+        F: FnMut(AppId, &Process),
+    {
+        self.process_each_enumerate(|i, process| match process.current_state() {
+            process::State::Fault | process::State::StartFailed => {
+                closure(AppId::new(self, i), process);
+            }
+            _ => {}
+        });
+    }
+
+    /// Register an observer to be told when any process faults or is
+    /// restarted.
+    pub fn set_process_state_observer(&self, observer: &'static ProcessObserver) {
+        self.process_observer.set(Some(observer));
+    }
+
+    /// Pass every syscall processes make to `logger`, e.g. to trace them over
+    /// a UART. Without a logger syscalls are not traced.
+    pub fn set_syscall_logger(&self, logger: &'static SyscallLogger) {
+        self.syscall_logger.set(Some(logger));
+    }
+
+    /// Persist each process's restart count in `store`, so that it survives
+    /// a reboot. Only restarts after a fault are counted.
+    pub fn set_restart_counter_store(&self, store: &'static RestartCounterStore) {
+        self.restart_counter_store.set(Some(store));
+    }
+
+    crate fn record_persistent_restart(&self, process: &Process) {
+        self.restart_counter_store
+            .get()
+            .map(|store| store.increment_restart_count(process.package_name));
+    }
+
+    /// Check the signature of each process loaded from now on with
+    /// `verifier`, so that it must be set before processes are loaded.
+    /// Without a verifier no process is marked as verified.
+    pub fn set_app_verifier(&self, verifier: &'static AppVerifier) {
+        self.app_verifier.set(Some(verifier));
+    }
+
+    /// Give the kernel slots to load processes into while it is running, with
+    /// `try_load_process_at()`. They are numbered after the slots of the
+    /// processes array passed to `Kernel::new()`.
+    ///
+    /// The processes array is filled in by `load_processes()` before the
+    /// kernel runs, so it cannot take processes later.
+    pub fn set_runtime_process_slots(
+        &self,
+        slots: &'static [Cell<Option<&'static Process<'static>>>],
+    ) {
+        self.runtime_slots.set(slots);
+    }
+
+    crate fn verify_app(&self, short_app_id: u32, signed: [&[u8]; 3], signature: &[u8]) -> bool {
+        self.app_verifier.get().map_or(false, |verifier| {
+            verifier.verify(short_app_id, signed, signature)
+        })
+    }
+
+    crate fn notify_process_faulted(&'static self, process: &Process) {
+        self.process_index(process).map(|idx| {
+            self.log_event(KernelEventKind::Faulted, idx);
+            self.process_observer
+                .get()
+                .map(|observer| observer.process_faulted(AppId::new(self, idx)));
+        });
+    }
+
+    crate fn notify_process_restarted(&'static self, process: &Process) {
+        self.process_index(process).map(|idx| {
+            self.log_event(KernelEventKind::Restarted, idx);
+            self.process_observer
+                .get()
+                .map(|observer| observer.process_restarted(AppId::new(self, idx)));
+        });
+    }
+
+    /// Set the nearest deadline of the pending software timers, in the units
+    /// of the chip's alarm clock, or `None` if there are none. While a deadline
+    /// is set the kernel sleeps with `Chip::sleep_until()` instead of
+    /// `Chip::sleep()`, so the chip can wake up in time to service it. This is
+    /// meant for boards that multiplex soft timers over one alarm, whose
+    /// owner keeps the deadline up to date.
+    pub fn set_next_wakeup(&self, deadline: Option<u32>) {
+        self.next_wakeup.set(deadline);
+    }
+
+    /// The deadline set with `set_next_wakeup()`, if any.
+    pub fn next_wakeup(&self) -> Option<u32> {
+        self.next_wakeup.get()
+    }
+
+    /// Start recording scheduling events into `buffer`, which holds one
+    /// fewer event than its length. Recording is off until this is called.
+    pub fn set_event_log(&self, buffer: &'static mut [KernelEvent]) {
+        self.event_log.replace(RingBuffer::new(buffer));
+    }
+
+    /// Remove and return the recorded events, oldest first.
+    pub fn drain_event_log(&self) -> EventLogDrain {
+        EventLogDrain { kernel: self }
+    }
+
+    fn log_event(&self, kind: KernelEventKind, process_index: usize) {
+        let event = KernelEvent {
+            kind: kind,
+            process_index: process_index,
+            tick: self.ticks.get(),
+        };
+        self.event_log.map(|log| {
+            if log.is_full() {
+                log.dequeue();
+            }
+            log.enqueue(event);
+        });
+    }
+
+    /// Find which slot of the processes array holds `process`.
+    fn process_index(&self, process: &Process) -> Option<usize> {
+        let target = process as *const Process as *const u8;
+        self.process_slots()
+            .position(|slot| slot.map_or(false, |p| p as *const Process as *const u8 == target))
+    }
+
+    /// Every process slot in order, first those of the processes array and
+    /// then the runtime slots, so that the position of a slot is its index.
+    fn process_slots(&self) -> impl Iterator<Item = Option<&'static Process<'static>>> {
+        self.processes
+            .iter()
+            .cloned()
+            .chain(self.runtime_slots.get().iter().map(|slot| slot.get()))
+    }
+
+    /// The process in slot `index`, if there is one.
+    fn process_at(&self, index: usize) -> Option<&'static Process<'static>> {
+        match self.processes.get(index) {
+            Some(process) => *process,
+            None => self
+                .runtime_slots
+                .get()
+                .get(index - self.processes.len())
+                .and_then(|slot| slot.get()),
+        }
+    }
+
     /// Run a closure on a specific process if it exists. If the process does
     /// not exist (i.e. it is `None` in the `processes` array) then `default`
     /// will be returned. Otherwise the closure will executed and passed a
@@ -77,23 +541,38 @@ impl Kernel {
     where
         F: FnOnce(&Process) -> R,
     {
-        if process_index > self.processes.len() {
-            return default;
+        match self.process_at(process_index) {
+            Some(process) => closure(process),
+            _ => default,
+        }
+    }
+
+    /// Run a closure on the process `appid` refers to. If that process no
+    /// longer exists, because it was restarted or its slot now holds a
+    /// different process, `default` is returned instead.
+    ///
+    /// Capsules use this to reach the process behind an `AppId`, e.g. to
+    /// inspect its memory layout. Prefer it over looking processes up by slot
+    /// index, as it cannot reach a different process than `appid` named.
+    pub fn with_process<F, R>(&self, appid: AppId, default: R, closure: F) -> R
+    where
+        F: FnOnce(&Process) -> R,
+    {
+        match self.process_at(appid.idx()) {
+            Some(process) if process.generation() == appid.generation() => closure(process),
+            _ => default,
         }
-        self.processes[process_index]
-            .as_ref()
-            .map_or(default, |process| closure(process))
     }
 
     /// Run a closure on every valid process. This will iterate the array of
     /// processes and call the closure on every process that exists.
-    crate fn process_each_enumerate<F>(&self, closure: F)
+    crate fn process_each_enumerate<F>(&self, mut closure: F)
     where
-        F: Fn(usize, &Process),
+        F: FnMut(usize, &Process),
     {
-        for (i, process) in self.processes.iter().enumerate() {
+        for (i, process) in self.process_slots().enumerate() {
             match process {
-                Some(ref p) => {
+                Some(p) => {
                     closure(i, p);
                 }
                 None => {}
@@ -109,9 +588,9 @@ impl Kernel {
     where
         F: Fn(usize, &Process) -> ReturnCode,
     {
-        for (i, process) in self.processes.iter().enumerate() {
+        for (i, process) in self.process_slots().enumerate() {
             match process {
-                Some(ref p) => {
+                Some(p) => {
                     let ret = closure(i, p);
                     if ret != ReturnCode::FAIL {
                         return ret;
@@ -123,9 +602,73 @@ impl Kernel {
         ReturnCode::FAIL
     }
 
+    /// Return a new process generation, different from any handed out
+    /// before.
+    crate fn next_process_generation(&self) -> usize {
+        self.process_generation.increment();
+        self.process_generation.get()
+    }
+
+    /// Ask the scheduler to run the process in slot `process_index` first on
+    /// its next pass. Returns `false` if there is no such process.
+    crate fn set_next_process_hint(&self, process_index: usize) -> bool {
+        if self.process_map_or(false, process_index, |_| true) {
+            self.next_process_hint.set(Some(process_index));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Print the scheduler's global state: how much work is outstanding and
+    /// the index, name and state of every process. This is meant for panic
+    /// handlers, alongside the per-process `fault_str`, so it is kept short
+    /// for slow UARTs.
+    pub fn panic_dump<W: Write>(&self, writer: &mut W) {
+        let occupied = self.process_slots().filter(|p| p.is_some()).count();
+        let _ = writer.write_fmt(format_args!(
+            "Kernel work: {}  processes: {}/{}\r\n",
+            self.work.get(),
+            occupied,
+            self.number_of_process_slots()
+        ));
+        self.process_each_enumerate(|i, process| {
+            let _ = writer.write_fmt(format_args!(
+                " {}: {} {:?}\r\n",
+                i,
+                process.package_name,
+                process.current_state()
+            ));
+        });
+    }
+
+    /// Print a table of the processes with one line per process: its index,
+    /// name, state, syscall count, restart count and the bytes of grant
+    /// memory it uses. This is the short equivalent of `ps` for a debugging
+    /// console, as opposed to the detailed per-process `statistics_str`.
+    /// Names longer than the name column are cut off to keep the columns
+    /// aligned.
+    pub fn debug_process_table<W: Write>(&self, writer: &mut W) {
+        let _ = writer.write_fmt(format_args!(
+            "{:>3}  {:<16}  {:<11}  {:>8}  {:>8}  {:>6}\r\n",
+            "#", "Name", "State", "Syscalls", "Restarts", "Grant"
+        ));
+        self.process_each_enumerate(|i, process| {
+            let _ = writer.write_fmt(format_args!(
+                "{:>3}  {:<16.16}  {:<11}  {:>8}  {:>8}  {:>6}\r\n",
+                i,
+                process.package_name,
+                process.current_state().name(),
+                process.syscall_count(),
+                process.get_restart_count(),
+                process.grant_bytes_used()
+            ));
+        });
+    }
+
     /// Return how many processes this board supports.
     crate fn number_of_process_slots(&self) -> usize {
-        self.processes.len()
+        self.processes.len() + self.runtime_slots.get().len()
     }
 
     /// Create a new grant. This is used in board initialization to setup grants
@@ -143,9 +686,43 @@ impl Kernel {
         // Create and return a new grant.
         let grant_index = self.grant_counter.get();
         self.grant_counter.increment();
+        if grant_index < MAX_PREALLOCATED_GRANTS {
+            let mut allocators = self.grant_allocators.get();
+            allocators[grant_index] = Some(grant::allocate_grant::<T>);
+            self.grant_allocators.set(allocators);
+        }
         Grant::new(self, grant_index)
     }
 
+    /// Allocate every grant for the process `appid` now, rather than the
+    /// first time a capsule enters each one. For deployments that want a
+    /// process that runs out of grant memory to do so at a predictable time,
+    /// e.g. right after it is loaded, and not halfway through an operation.
+    /// Only the first `MAX_PREALLOCATED_GRANTS` grants created can be
+    /// allocated this way.
+    pub fn preallocate_grants(&self, appid: AppId) -> Result<(), process::Error> {
+        let grant_count = self.grant_counter.get();
+        if grant_count > MAX_PREALLOCATED_GRANTS {
+            debug!(
+                "Only {} of {} grants can be preallocated",
+                MAX_PREALLOCATED_GRANTS, grant_count
+            );
+        }
+        self.with_process(appid, Err(process::Error::NoSuchApp), |process| {
+            if !process.is_active() {
+                return Err(process::Error::NoSuchApp);
+            }
+            for (grant_num, allocator) in self.grant_allocators.get().iter().enumerate() {
+                if let Some(allocate) = *allocator {
+                    unsafe {
+                        allocate(process, grant_num)?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
     /// Returns the number of grants that have been setup in the system and
     /// marks the grants as "finalized". This means that no more grants can
     /// be created because data structures have been setup based on the number
@@ -158,35 +735,162 @@ impl Kernel {
         self.grant_counter.get()
     }
 
+    /// Load a single app from `flash_addr` while the kernel is running, for
+    /// example after it has been written to flash by an update capsule. The
+    /// app is placed in the first empty slot given to
+    /// `set_runtime_process_slots()` and gets RAM from `app_memory`. On success the new process is scheduled to
+    /// start and its `AppId` is returned.
+    ///
+    /// Returns `NoFreeProcessSlot` if every runtime slot is taken,
+    /// `IncompatibleKernelVersion` if the app needs a newer kernel, and
+    /// `OutOfMemory` if the process does not fit in `app_memory`. Any other
+    /// problem with the app's header, including there being no valid,
+    /// enabled app at `flash_addr`, returns `InvalidHeader`.
+    ///
+    /// # Safety
+    ///
+    /// `flash_addr` must point to readable memory holding the app, which
+    /// must not change while the process exists.
+    pub unsafe fn try_load_process_at(
+        &'static self,
+        flash_addr: *const u8,
+        app_memory: &'static mut [u8],
+        fault_response: FaultResponse,
+    ) -> Result<AppId, process::Error> {
+        match tbfheader::parse_and_validate_tbf_header(flash_addr) {
+            Some(ref header) if header.is_app() && header.enabled() => {}
+            _ => return Err(process::Error::InvalidHeader),
+        }
+
+        let runtime_slots = self.runtime_slots.get();
+        let slot = runtime_slots
+            .iter()
+            .position(|slot| slot.get().is_none())
+            .ok_or(process::Error::NoFreeProcessSlot)?;
+
+        let (process, _, _) = Process::create(
+            self,
+            flash_addr,
+            app_memory.as_mut_ptr(),
+            app_memory.len(),
+            fault_response,
+            MemoryAllocation::BottomUp,
+        );
+        runtime_slots[slot].set(Some(process?));
+        Ok(AppId::new(self, self.processes.len() + slot))
+    }
+
     /// Main loop.
     pub fn kernel_loop<P: Platform, C: Chip>(
         &'static self,
         platform: &P,
         chip: &mut C,
         ipc: Option<&ipc::IPC>,
+    ) {
+        self.kernel_loop_with_idle(platform, chip, ipc, || {});
+    }
+
+    /// Main loop, calling `idle` whenever all processes are blocked and no
+    /// interrupts are pending, before the chip is put to sleep. Boards can use
+    /// this for low priority work such as flushing logs or blinking a
+    /// heartbeat LED. `idle` runs with interrupts enabled, and the kernel only
+    /// sleeps afterwards if there is still nothing to do.
+    pub fn kernel_loop_with_idle<P: Platform, C: Chip, F: FnMut()>(
+        &'static self,
+        platform: &P,
+        chip: &mut C,
+        ipc: Option<&ipc::IPC>,
+        mut idle: F,
     ) {
         loop {
             unsafe {
                 chip.service_pending_interrupts();
 
-                for (i, p) in self.processes.iter().enumerate() {
-                    p.as_ref().map(|process| {
-                        self.do_process(
-                            platform,
-                            chip,
-                            process,
-                            callback::AppId::new(self, i),
-                            ipc,
-                        );
+                // Run a process that was asked for first, as long as it has
+                // something to do.
+                self.next_process_hint.take().map(|i| {
+                    self.process_map_or((), i, |process| {
+                        if process.not_runnable_reason().is_none() {
+                            self.do_process(
+                                platform,
+                                chip,
+                                process,
+                                callback::AppId::new(self, i),
+                                ipc,
+                            );
+                        }
                     });
-                    if chip.has_pending_interrupts() {
-                        break;
+                });
+
+                match self.scheduling_policy.get() {
+                    SchedulingPolicy::RoundRobin => {
+                        for (i, p) in self.process_slots().enumerate() {
+                            p.map(|process| {
+                                self.do_process(
+                                    platform,
+                                    chip,
+                                    process,
+                                    callback::AppId::new(self, i),
+                                    ipc,
+                                );
+                            });
+                            if chip.has_pending_interrupts() {
+                                break;
+                            }
+                        }
+                    }
+                    SchedulingPolicy::Priority => {
+                        // Sweep over the processes one priority level at a
+                        // time, from the highest level down.
+                        let mut level = self.next_priority_level(None);
+                        'sweep: while let Some(priority) = level {
+                            for (i, p) in self.process_slots().enumerate() {
+                                p.map(|process| {
+                                    if process.priority() == priority {
+                                        self.do_process(
+                                            platform,
+                                            chip,
+                                            process,
+                                            callback::AppId::new(self, i),
+                                            ipc,
+                                        );
+                                    }
+                                });
+                                if chip.has_pending_interrupts() {
+                                    break 'sweep;
+                                }
+                            }
+                            level = self.next_priority_level(Some(priority));
+                        }
+                    }
+                    SchedulingPolicy::EarliestDeadlineFirst => {
+                        // Run one process, then pick again, as servicing
+                        // interrupts may have given an earlier deadline
+                        // process work to do.
+                        self.earliest_deadline_process().map(|i| {
+                            self.process_map_or((), i, |process| {
+                                self.do_process(
+                                    platform,
+                                    chip,
+                                    process,
+                                    callback::AppId::new(self, i),
+                                    ipc,
+                                );
+                            });
+                        });
                     }
                 }
 
+                if !chip.has_pending_interrupts() && self.processes_blocked() {
+                    idle();
+                }
+
                 chip.atomic(|| {
                     if !chip.has_pending_interrupts() && self.processes_blocked() {
-                        chip.sleep();
+                        match self.next_wakeup.get() {
+                            Some(deadline) => chip.sleep_until(deadline),
+                            None => chip.sleep(),
+                        }
                     }
                 });
             };
@@ -198,7 +902,7 @@ impl Kernel {
         platform: &P,
         chip: &mut C,
         process: &Process,
-        appid: AppId,
+        mut appid: AppId,
         ipc: Option<&::ipc::IPC>,
     ) {
         let systick = chip.systick();
@@ -206,29 +910,61 @@ impl Kernel {
         systick.set_timer(KERNEL_TICK_DURATION_US);
         systick.enable(true);
 
+        // Number of syscalls this process has made in this timeslice.
+        let mut syscalls_this_timeslice = 0;
+
+        // The MPU is programmed for this process on the first switch into it.
+        // Afterwards nothing else touches the MPU until this timeslice ends,
+        // so it only has to be reprogrammed if the process's memory layout
+        // changed, e.g. it moved its break or a capsule allocated grant memory.
+        let mut mpu_configured = false;
+
         loop {
             if chip.has_pending_interrupts()
                 || systick.overflowed()
                 || !systick.greater_than(MIN_QUANTA_THRESHOLD_US)
+                || self
+                    .syscall_budget
+                    .get()
+                    .map_or(false, |budget| syscalls_this_timeslice >= budget)
             {
                 break;
             }
 
             match process.current_state() {
                 process::State::Running => {
-                    process.setup_mpu(chip.mpu());
+                    if !mpu_configured || process.mpu_needs_update() {
+                        process.setup_mpu(chip.mpu());
+                        mpu_configured = true;
+                    }
                     chip.mpu().enable_mpu();
                     systick.enable(true);
+                    self.context_switches.set(self.context_switches.get() + 1);
+                    self.log_event(KernelEventKind::Scheduled, appid.idx());
                     process.switch_to();
                     systick.enable(false);
                     chip.mpu().disable_mpu();
                 }
+                process::State::Yielded if process.task_queues_corrupt() => {
+                    // Dequeuing would read outside the queue, so its tasks
+                    // cannot be recovered. Faulting the process drops them,
+                    // giving back the kernel work they account for.
+                    debug!(
+                        "{}: task queue corrupted, faulting the process",
+                        process.package_name
+                    );
+                    process.fault_state();
+                    break;
+                }
                 process::State::Yielded => match process.dequeue_task() {
                     None => break,
                     Some(cb) => {
                         match cb {
                             Task::FunctionCall(ccb) => {
                                 process.push_function_call(ccb);
+                                // Pushing the call faults the process if its
+                                // stack is bad, which may restart it.
+                                appid = AppId::new(appid.kernel(), appid.idx());
                             }
                             Task::IPC((otherapp, ipc_type)) => {
                                 ipc.map_or_else(
@@ -243,35 +979,66 @@ impl Kernel {
                                     },
                                 );
                             }
+                            Task::Terminate => {
+                                process.terminate();
+                                break;
+                            }
                         }
                         continue;
                     }
                 },
-                process::State::Fault => {
+                process::State::Fault
+                | process::State::StartFailed
+                | process::State::Terminated => {
                     // we should never be scheduling a process in fault
                     panic!("Attempted to schedule a faulty process");
                 }
             }
 
-            if !process.syscall_fired() {
-                break;
-            }
-
-            // check if the app had a fault
-            if process.app_fault() {
-                // let process deal with it as appropriate
-                process.fault_state();
-                continue;
+            match process.context_switch_reason() {
+                ContextSwitchReason::Interrupted => {
+                    // The process did not finish its work, but an interrupt
+                    // needs servicing or its timeslice is over.
+                    if systick.overflowed() {
+                        self.ticks.set(self.ticks.get() + 1);
+                    }
+                    self.log_event(KernelEventKind::Preempted, appid.idx());
+                    break;
+                }
+                ContextSwitchReason::Fault => {
+                    // let process deal with it as appropriate
+                    process.fault_state();
+                    if !process.is_active() {
+                        // The process was not restarted, and will not run
+                        // again in this timeslice.
+                        break;
+                    }
+                    // A restart gives the process a new generation, and the
+                    // rest of this timeslice must use the `AppId` it has now.
+                    appid = AppId::new(appid.kernel(), appid.idx());
+                    continue;
+                }
+                ContextSwitchReason::SyscallFired => {
+                    self.log_event(KernelEventKind::Syscall, appid.idx());
+                }
             }
 
             // process had a system call, count it
-            process.incr_syscall_count();
+            process.incr_syscall_count(self.ticks.get());
+            syscalls_this_timeslice += 1;
+            self.syscall_logger.get().map(|logger| {
+                process.svc_number().map(|syscall| {
+                    let args = (process.r0(), process.r1(), process.r2(), process.r3());
+                    logger.log(appid, syscall, args);
+                })
+            });
             match process.svc_number() {
                 Some(Syscall::MEMOP) => {
                     let res = memop::memop(process);
                     process.set_return_code(res);
                 }
                 Some(Syscall::YIELD) => {
+                    self.log_event(KernelEventKind::Yielded, appid.idx());
                     process.yield_state();
                     process.pop_syscall_stack();
 
@@ -288,34 +1055,61 @@ impl Kernel {
                     let callback =
                         callback_ptr.map(|ptr| Callback::new(appid, appdata, ptr.cast()));
 
-                    let res = platform.with_driver(driver_num, |driver| match driver {
-                        Some(d) => d.subscribe(subdriver_num, callback, appid),
-                        None => ReturnCode::ENODEVICE,
+                    // A process whose TBF header restricts the drivers it may
+                    // use sees any other driver as missing.
+                    let res = platform.with_driver(driver_num, |driver| {
+                        match driver.filter(|_| process.has_driver_permission(driver_num)) {
+                            Some(d) => d.subscribe(subdriver_num, callback, appid),
+                            None => ReturnCode::ENODEVICE,
+                        }
                     });
                     process.set_return_code(res);
                 }
                 Some(Syscall::COMMAND) => {
-                    let res = platform.with_driver(process.r0(), |driver| match driver {
-                        Some(d) => d.command(process.r1(), process.r2(), process.r3(), appid),
-                        None => ReturnCode::ENODEVICE,
+                    let res = platform.with_driver(process.r0(), |driver| {
+                        match driver.filter(|_| process.has_driver_permission(process.r0())) {
+                            Some(d) => d.command_extended(
+                                process.r1(),
+                                process.r2(),
+                                process.r3(),
+                                process.r4(),
+                                appid,
+                            ),
+                            None => CommandReturn::failure(ReturnCode::ENODEVICE),
+                        }
                     });
-                    process.set_return_code(res);
+                    process.set_command_return(res);
                 }
                 Some(Syscall::ALLOW) => {
                     let res = platform.with_driver(process.r0(), |driver| {
-                        match driver {
+                        match driver.filter(|_| process.has_driver_permission(process.r0())) {
                             Some(d) => {
                                 let start_addr = process.r2() as *mut u8;
                                 if start_addr != ptr::null_mut() {
+                                    // A zero-length buffer gives the driver
+                                    // no access to anything, so it is
+                                    // accepted wherever it points.
                                     let size = process.r3();
-                                    if process.in_exposed_bounds(start_addr, size) {
-                                        let slice =
-                                            AppSlice::new(start_addr as *mut u8, size, appid);
+                                    if size == 0 || process.in_exposed_bounds(start_addr, size) {
+                                        let slice = AppSlice::new(
+                                            start_addr as *mut u8,
+                                            size,
+                                            appid,
+                                            process.r0(),
+                                            process.r1(),
+                                            process.allow_shared(),
+                                        );
                                         d.allow(appid, process.r1(), Some(slice))
                                     } else {
                                         ReturnCode::EINVAL /* memory not allocated to process */
                                     }
                                 } else {
+                                    // The process is unsharing the buffer it
+                                    // allowed before. The driver may still
+                                    // hold an `AppSlice` for it, so mark
+                                    // that slice as empty before the process
+                                    // can reuse the memory.
+                                    process.allow_unshared(process.r0(), process.r1());
                                     d.allow(appid, process.r1(), None)
                                 }
                             }
@@ -330,3 +1124,16 @@ impl Kernel {
         systick.reset();
     }
 }
+
+/// Iterator that removes events from the kernel event log, oldest first.
+pub struct EventLogDrain<'a> {
+    kernel: &'a Kernel,
+}
+
+impl Iterator for EventLogDrain<'a> {
+    type Item = KernelEvent;
+
+    fn next(&mut self) -> Option<KernelEvent> {
+        self.kernel.event_log.and_then(|log| log.dequeue())
+    }
+}
@@ -1,8 +1,11 @@
 //! Tock syscall number definitions.
 
+use callback::AppId;
+use returncode::ReturnCode;
+
 /// The syscall number assignments.
 #[derive(Copy, Clone, Debug)]
-crate enum Syscall {
+pub enum Syscall {
     /// Return to the kernel to allow other processes to execute or to wait for
     /// interrupts and callbacks.
     YIELD = 0,
@@ -19,3 +22,56 @@ crate enum Syscall {
     /// Various memory operations.
     MEMOP = 4,
 }
+
+/// Receives every syscall processes make, for tracing them without a
+/// debugger. A board can forward them to a UART or record them in a buffer.
+/// Set with `Kernel::set_syscall_logger()`.
+pub trait SyscallLogger {
+    /// The process `app` made `syscall` with `args` in registers `r0` to
+    /// `r3`. This is called before the kernel handles the syscall.
+    fn log(&self, app: AppId, syscall: Syscall, args: (usize, usize, usize, usize));
+}
+
+/// The result of a `command` syscall. The return code is passed back to the
+/// process in `r0`, and a command that succeeds can also pass back a 32-bit
+/// value in `r1`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CommandReturn {
+    crate code: ReturnCode,
+    crate value: Option<u32>,
+}
+
+impl CommandReturn {
+    /// The command succeeded.
+    pub fn success() -> CommandReturn {
+        CommandReturn {
+            code: ReturnCode::SUCCESS,
+            value: None,
+        }
+    }
+
+    /// The command succeeded, and `value` is passed back in `r1`.
+    pub fn success_u32(value: u32) -> CommandReturn {
+        CommandReturn {
+            code: ReturnCode::SUCCESS,
+            value: Some(value),
+        }
+    }
+
+    /// The command failed with `code`.
+    pub fn failure(code: ReturnCode) -> CommandReturn {
+        CommandReturn {
+            code: code,
+            value: None,
+        }
+    }
+}
+
+impl From<ReturnCode> for CommandReturn {
+    fn from(code: ReturnCode) -> CommandReturn {
+        CommandReturn {
+            code: code,
+            value: None,
+        }
+    }
+}
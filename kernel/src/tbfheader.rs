@@ -64,6 +64,12 @@ crate enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     Unused = 5,
+    TbfHeaderKernelVersion = 8,
+    TbfHeaderFaultHandler = 10,
+    TbfHeaderInitialStack = 11,
+    TbfHeaderDriverPermissions = 12,
+    TbfHeaderAppId = 13,
+    TbfHeaderSignature = 14,
 }
 
 /// The TLV header (T and L).
@@ -97,6 +103,36 @@ crate struct TbfHeaderV2WriteableFlashRegion {
     writeable_flash_region_size: u32,
 }
 
+/// The oldest kernel version the app is compatible with.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+crate struct TbfHeaderV2KernelVersion {
+    major: u16,
+    minor: u16,
+}
+
+/// Where the app's fault handler is, relative to the start of the binary.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+crate struct TbfHeaderV2FaultHandler {
+    handler_offset: u32,
+}
+
+/// How much of its RAM the app uses for its stack and static data before it
+/// first calls brk.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+crate struct TbfHeaderV2InitialStack {
+    initial_stack_size: u32,
+}
+
+/// A short identifier for the app, for access control decisions.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+crate struct TbfHeaderV2AppId {
+    short_id: u32,
+}
+
 /// PIC fields for kernel provided PIC fixup.
 ///
 /// If an app wants the kernel to do the PIC fixup for it, it must pass this
@@ -257,6 +293,149 @@ impl TbfHeader {
             _ => (0, 0),
         }
     }
+
+    /// Get the oldest kernel version, as `(major, minor)`, the app declares it
+    /// is compatible with, if its header contains a kernel version block.
+    crate fn get_kernel_version(&self) -> Option<(u16, u16)> {
+        self.get_tlv(TbfHeaderTypes::TbfHeaderKernelVersion as u16)
+            .and_then(|value| {
+                if value.len() == mem::size_of::<TbfHeaderV2KernelVersion>() {
+                    let version = unsafe { &*(value.as_ptr() as *const TbfHeaderV2KernelVersion) };
+                    Some((version.major, version.minor))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Get the offset from the beginning of the app's flash region of the
+    /// function to run when the app faults, if its header declares one and
+    /// the offset does not overflow.
+    crate fn get_fault_handler_offset(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => self
+                .get_tlv(TbfHeaderTypes::TbfHeaderFaultHandler as u16)
+                .and_then(|value| {
+                    if value.len() == mem::size_of::<TbfHeaderV2FaultHandler>() {
+                        let handler =
+                            unsafe { &*(value.as_ptr() as *const TbfHeaderV2FaultHandler) };
+                        handler
+                            .handler_offset
+                            .checked_add(hd.base.header_size as u32)
+                    } else {
+                        None
+                    }
+                }),
+            _ => None,
+        }
+    }
+
+    /// Get the size in bytes of the memory the app starts out with, below its
+    /// initial stack pointer and break, if its header specifies one.
+    crate fn get_initial_stack_size(&self) -> Option<u32> {
+        self.get_tlv(TbfHeaderTypes::TbfHeaderInitialStack as u16)
+            .and_then(|value| {
+                if value.len() == mem::size_of::<TbfHeaderV2InitialStack>() {
+                    let initial = unsafe { &*(value.as_ptr() as *const TbfHeaderV2InitialStack) };
+                    Some(initial.initial_stack_size)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Get the driver numbers the app is allowed to use, if its header
+    /// restricts them. A malformed permissions block allows no drivers.
+    crate fn get_driver_permissions(&self) -> Option<&'static [u32]> {
+        self.get_tlv(TbfHeaderTypes::TbfHeaderDriverPermissions as u16)
+            .map(|value| {
+                if value.len() % mem::size_of::<u32>() == 0 {
+                    let number_drivers = value.len() / mem::size_of::<u32>();
+                    unsafe { slice::from_raw_parts(value.as_ptr() as *const u32, number_drivers) }
+                } else {
+                    &[]
+                }
+            })
+    }
+
+    /// Get the app's short identifier, if its header contains an app id block.
+    crate fn get_short_app_id(&self) -> Option<u32> {
+        self.get_tlv(TbfHeaderTypes::TbfHeaderAppId as u16)
+            .and_then(|value| {
+                if value.len() == mem::size_of::<TbfHeaderV2AppId>() {
+                    let app_id = unsafe { &*(value.as_ptr() as *const TbfHeaderV2AppId) };
+                    Some(app_id.short_id)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Get the signature in the header, if it contains one, along with the
+    /// bytes it signs. The kernel does not interpret the signature itself.
+    ///
+    /// The signature covers all of the app's TBF, `app_flash`, except for the
+    /// header checksum, which depends on the signature, and the value of the
+    /// signature block. The signed bytes are returned as the three parts
+    /// before, between and after those two.
+    crate fn get_signature(
+        &self,
+        app_flash: &'static [u8],
+    ) -> Option<([&'static [u8]; 3], &'static [u8])> {
+        self.get_tlv(TbfHeaderTypes::TbfHeaderSignature as u16)
+            .map(|signature| {
+                // The checksum is the last field of `TbfHeaderV2Base`, and
+                // TLV blocks, being inside the header, come after it.
+                let checksum_end = mem::size_of::<TbfHeaderV2Base>();
+                let checksum_start = checksum_end - mem::size_of::<u32>();
+                let signature_start = signature.as_ptr() as usize - app_flash.as_ptr() as usize;
+                let signature_end = signature_start + signature.len();
+                let signed = [
+                    &app_flash[..checksum_start],
+                    &app_flash[checksum_end..signature_start],
+                    &app_flash[signature_end..],
+                ];
+                (signed, signature)
+            })
+    }
+
+    /// Get the value of the first TLV block of type `tlv_type` in the header,
+    /// including TLV types the kernel does not otherwise parse. Only version 2
+    /// headers have TLV blocks.
+    crate fn get_tlv(&self, tlv_type: u16) -> Option<&'static [u8]> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => unsafe {
+                let address = hd.base as *const TbfHeaderV2Base as *const u8;
+                let header_size = hd.base.header_size as usize;
+                let tlv_header_size = mem::size_of::<TbfHeaderTlv>();
+
+                let mut offset = mem::size_of::<TbfHeaderV2Base>();
+                while offset + tlv_header_size <= header_size {
+                    // Read the type as a raw `u16` since it may not be one of
+                    // the `TbfHeaderTypes` the kernel knows about.
+                    let tipe = *(address.offset(offset as isize) as *const u16);
+                    let length = *(address.offset(offset as isize + 2) as *const u16) as usize;
+                    offset += tlv_header_size;
+
+                    if offset + length > header_size {
+                        // Truncated block, stop looking.
+                        return None;
+                    }
+                    if tipe == tlv_type {
+                        return Some(slice::from_raw_parts(
+                            address.offset(offset as isize),
+                            length,
+                        ));
+                    }
+
+                    // All TLV blocks are padded to 4 bytes.
+                    offset += align4!(length);
+                }
+                None
+            },
+            _ => None,
+        }
+    }
 }
 
 /// Converts a pointer to memory to a TbfHeader struct
@@ -396,6 +575,16 @@ crate unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfH
                                     let _ = str::from_utf8(package_name_byte_array).map(|name_str| { app_name_str = name_str; });
                                 }
                             }
+                            // Only looked up on demand, see `get_kernel_version()`,
+                            // `get_fault_handler_offset()`, `get_initial_stack_size()`,
+                            // `get_driver_permissions()`, `get_short_app_id()` and
+                            // `get_signature()`.
+                            TbfHeaderTypes::TbfHeaderKernelVersion => {}
+                            TbfHeaderTypes::TbfHeaderFaultHandler => {}
+                            TbfHeaderTypes::TbfHeaderInitialStack => {}
+                            TbfHeaderTypes::TbfHeaderDriverPermissions => {}
+                            TbfHeaderTypes::TbfHeaderAppId => {}
+                            TbfHeaderTypes::TbfHeaderSignature => {}
                             TbfHeaderTypes::Unused => {}
                         }
                     }